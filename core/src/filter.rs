@@ -0,0 +1,352 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+
+/// A file as seen by a filter: its path, relative to the root of the source
+/// where applicable, and its metadata when known.
+pub struct FileProperties<'a> {
+    pub path: &'a Path,
+    pub size: Option<u64>,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl<'a> FileProperties<'a> {
+    /// Properties of a local file, with the metadata read from the file system
+    pub fn local(path: &'a Path) -> FileProperties<'a> {
+        let metadata = std::fs::metadata(path).ok();
+
+        FileProperties {
+            path,
+            size: metadata.as_ref().map(|m| m.len()),
+            modified: metadata.and_then(|m| m.modified().ok()).map(DateTime::<Utc>::from),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegexFilter {
+    #[serde(with = "serde_regex")]
+    pattern: Regex,
+}
+
+/// Glob pattern that is compiled when the configuration is loaded
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct GlobPattern {
+    pattern: String,
+    matcher: GlobMatcher,
+}
+
+impl TryFrom<String> for GlobPattern {
+    type Error = globset::Error;
+
+    fn try_from(pattern: String) -> Result<GlobPattern, globset::Error> {
+        let matcher = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+
+        Ok(GlobPattern { pattern, matcher })
+    }
+}
+
+impl From<GlobPattern> for String {
+    fn from(glob: GlobPattern) -> String {
+        glob.pattern
+    }
+}
+
+impl fmt::Debug for GlobPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GlobPattern({})", self.pattern)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GlobFilter {
+    pattern: GlobPattern,
+}
+
+/// Filter on files, shared by directory sources, connections and the SFTP
+/// scanner. Sizes are in bytes, ages in milliseconds since the last
+/// modification. Size and age filters do not match files of which the
+/// metadata is unknown.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Filter {
+    /// Regular expression on the file name
+    Regex(RegexFilter),
+    /// Regular expression on the full path
+    PathRegex(RegexFilter),
+    /// Glob pattern on the file name, or on the full path when the pattern
+    /// contains a '/', e.g. '*.xml' or 'reports/**/*.csv'
+    Glob(GlobFilter),
+    MinSize(u64),
+    MaxSize(u64),
+    MinAge(u64),
+    MaxAge(u64),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    All,
+}
+
+impl Filter {
+    /// Evaluate the filter for a local file, reading its metadata from the
+    /// file system only when the filter needs it.
+    pub fn file_matches<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = path.as_ref();
+
+        let file = if self.needs_metadata() {
+            FileProperties::local(path)
+        } else {
+            FileProperties { path, size: None, modified: None }
+        };
+
+        self.matches(&file, Utc::now())
+    }
+
+    /// Evaluate the filter for a local file in a source directory, with path
+    /// filters applied to the path relative to that directory.
+    pub fn file_matches_in<R: AsRef<Path>, P: AsRef<Path>>(&self, root: R, path: P) -> bool {
+        let path = path.as_ref();
+        let relative_path = path.strip_prefix(root).unwrap_or(path);
+
+        let file = if self.needs_metadata() {
+            FileProperties { path: relative_path, ..FileProperties::local(path) }
+        } else {
+            FileProperties { path: relative_path, size: None, modified: None }
+        };
+
+        self.matches(&file, Utc::now())
+    }
+
+    /// Evaluate the filter for a file in the local storage, with path filters
+    /// applied to the path relative to `root`, the storage directory of its
+    /// source, and size and age filters to the metadata recorded for the file
+    /// rather than that of the stored copy.
+    pub fn stored_file_matches<R: AsRef<Path>, P: AsRef<Path>>(&self, root: R, path: P, size: u64, modified: DateTime<Utc>) -> bool {
+        let path = path.as_ref();
+
+        let file = FileProperties {
+            path: path.strip_prefix(root).unwrap_or(path),
+            size: Some(size),
+            modified: Some(modified),
+        };
+
+        self.matches(&file, Utc::now())
+    }
+
+    /// Evaluate the filter for a file at the time `now`
+    pub fn matches(&self, file: &FileProperties, now: DateTime<Utc>) -> bool {
+        match self {
+            Filter::Regex(r) => file.path.file_name()
+                .is_some_and(|file_name| r.pattern.is_match(&file_name.to_string_lossy())),
+            Filter::PathRegex(r) => r.pattern.is_match(&file.path.to_string_lossy()),
+            Filter::Glob(g) => {
+                if g.pattern.pattern.contains('/') {
+                    g.pattern.matcher.is_match(file.path)
+                } else {
+                    file.path.file_name().is_some_and(|file_name| g.pattern.matcher.is_match(file_name))
+                }
+            },
+            Filter::MinSize(min) => file.size.is_some_and(|size| size >= *min),
+            Filter::MaxSize(max) => file.size.is_some_and(|size| size <= *max),
+            Filter::MinAge(min) => age(file, now).is_some_and(|age| age >= *min as i64),
+            Filter::MaxAge(max) => age(file, now).is_some_and(|age| age <= *max as i64),
+            Filter::And(filters) => filters.iter().all(|f| f.matches(file, now)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(file, now)),
+            Filter::Not(filter) => !filter.matches(file, now),
+            Filter::All => true,
+        }
+    }
+
+    /// Whether the filter has size or age conditions
+    pub fn needs_metadata(&self) -> bool {
+        match self {
+            Filter::MinSize(_) | Filter::MaxSize(_) | Filter::MinAge(_) | Filter::MaxAge(_) => true,
+            Filter::And(filters) | Filter::Or(filters) => filters.iter().any(|f| f.needs_metadata()),
+            Filter::Not(filter) => filter.needs_metadata(),
+            Filter::Regex(_) | Filter::PathRegex(_) | Filter::Glob(_) | Filter::All => false,
+        }
+    }
+}
+
+/// Milliseconds since the last modification of a file
+fn age(file: &FileProperties, now: DateTime<Utc>) -> Option<i64> {
+    file.modified.map(|modified| (now - modified).num_milliseconds())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::{Duration, TimeZone};
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2021, 3, 1).and_hms(12, 0, 0)
+    }
+
+    fn file(path: &str, size: u64, age_ms: i64) -> (String, u64, DateTime<Utc>) {
+        (path.to_string(), size, now() - Duration::milliseconds(age_ms))
+    }
+
+    fn matches(filter: &Filter, file: &(String, u64, DateTime<Utc>)) -> bool {
+        let properties = FileProperties {
+            path: Path::new(&file.0),
+            size: Some(file.1),
+            modified: Some(file.2),
+        };
+
+        filter.matches(&properties, now())
+    }
+
+    fn regex(pattern: &str) -> RegexFilter {
+        RegexFilter { pattern: Regex::new(pattern).unwrap() }
+    }
+
+    fn glob(pattern: &str) -> Filter {
+        Filter::Glob(GlobFilter { pattern: GlobPattern::try_from(pattern.to_string()).unwrap() })
+    }
+
+    #[test]
+    fn regex_matches_file_name_only() {
+        let filter = Filter::Regex(regex("^data.*\\.xml$"));
+
+        assert!(matches(&filter, &file("in/data_1.xml", 10, 0)));
+        assert!(!matches(&filter, &file("data/report.xml", 10, 0)));
+    }
+
+    #[test]
+    fn path_regex_matches_full_path() {
+        let filter = Filter::PathRegex(regex("^reports/\\d{4}/.*\\.csv$"));
+
+        assert!(matches(&filter, &file("reports/2021/a.csv", 10, 0)));
+        assert!(!matches(&filter, &file("archive/reports/2021/a.csv", 10, 0)));
+        assert!(!matches(&filter, &file("reports/latest/a.csv", 10, 0)));
+    }
+
+    #[test]
+    fn glob_without_separator_matches_file_name() {
+        let filter = glob("*.xml");
+
+        assert!(matches(&filter, &file("a/b/c.xml", 10, 0)));
+        assert!(!matches(&filter, &file("a/b.xml/c.csv", 10, 0)));
+    }
+
+    #[test]
+    fn glob_with_separator_matches_path() {
+        let filter = glob("reports/**/*.csv");
+
+        assert!(matches(&filter, &file("reports/2021/03/a.csv", 10, 0)));
+        assert!(!matches(&filter, &file("other/2021/a.csv", 10, 0)));
+
+        let single_level = glob("reports/*.csv");
+
+        assert!(matches(&single_level, &file("reports/a.csv", 10, 0)));
+        assert!(!matches(&single_level, &file("reports/2021/a.csv", 10, 0)));
+    }
+
+    #[test]
+    fn size_bounds_are_inclusive() {
+        let min = Filter::MinSize(100);
+        let max = Filter::MaxSize(100);
+
+        assert!(matches(&min, &file("a", 100, 0)));
+        assert!(!matches(&min, &file("a", 99, 0)));
+        assert!(matches(&max, &file("a", 100, 0)));
+        assert!(!matches(&max, &file("a", 101, 0)));
+    }
+
+    #[test]
+    fn age_bounds_are_inclusive() {
+        let min = Filter::MinAge(60_000);
+        let max = Filter::MaxAge(60_000);
+
+        assert!(matches(&min, &file("a", 1, 60_000)));
+        assert!(!matches(&min, &file("a", 1, 59_999)));
+        assert!(matches(&max, &file("a", 1, 60_000)));
+        assert!(!matches(&max, &file("a", 1, 60_001)));
+    }
+
+    #[test]
+    fn unknown_metadata_does_not_match_size_or_age() {
+        let properties = FileProperties { path: Path::new("a.xml"), size: None, modified: None };
+
+        for filter in &[Filter::MinSize(0), Filter::MaxSize(u64::MAX), Filter::MinAge(0), Filter::MaxAge(u64::MAX)] {
+            assert!(!filter.matches(&properties, now()));
+        }
+    }
+
+    #[test]
+    fn and_requires_all() {
+        let filter = Filter::And(vec![glob("*.xml"), Filter::MinSize(10)]);
+
+        assert!(matches(&filter, &file("a.xml", 10, 0)));
+        assert!(!matches(&filter, &file("a.xml", 9, 0)));
+        assert!(!matches(&filter, &file("a.csv", 10, 0)));
+        assert!(matches(&Filter::And(vec![]), &file("a", 1, 0)));
+    }
+
+    #[test]
+    fn or_requires_any() {
+        let filter = Filter::Or(vec![glob("*.xml"), glob("*.csv")]);
+
+        assert!(matches(&filter, &file("a.xml", 1, 0)));
+        assert!(matches(&filter, &file("a.csv", 1, 0)));
+        assert!(!matches(&filter, &file("a.json", 1, 0)));
+        assert!(!matches(&Filter::Or(vec![]), &file("a", 1, 0)));
+    }
+
+    #[test]
+    fn not_inverts() {
+        let filter = Filter::Not(Box::new(Filter::PathRegex(regex("^tmp/"))));
+
+        assert!(matches(&filter, &file("data/a.xml", 1, 0)));
+        assert!(!matches(&filter, &file("tmp/a.xml", 1, 0)));
+    }
+
+    #[test]
+    fn all_matches_everything() {
+        assert!(matches(&Filter::All, &file("", 0, 0)));
+    }
+
+    #[test]
+    fn metadata_is_only_needed_for_size_and_age() {
+        assert!(!Filter::Or(vec![glob("*.xml"), Filter::All]).needs_metadata());
+        assert!(Filter::Not(Box::new(Filter::And(vec![Filter::MaxAge(1)]))).needs_metadata());
+    }
+
+    #[test]
+    fn local_file_metadata_is_read() {
+        let path = std::env::temp_dir().join(format!("cortex-filter-{}.xml", std::process::id()));
+
+        std::fs::write(&path, b"12345").unwrap();
+
+        let filter = Filter::And(vec![glob("*.xml"), Filter::MinSize(5), Filter::MaxSize(5), Filter::MaxAge(3_600_000)]);
+
+        assert!(filter.file_matches(&path));
+        assert!(!Filter::MinSize(6).file_matches(&path));
+
+        let relative = Filter::And(vec![glob(&format!("cortex-filter-{}.*", std::process::id())), Filter::MinSize(5)]);
+
+        assert!(relative.file_matches_in(std::env::temp_dir(), &path));
+        assert!(!Filter::PathRegex(regex("^/")).file_matches_in(std::env::temp_dir(), &path));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stored_file_uses_recorded_metadata() {
+        let filter = Filter::And(vec![glob("in/*.xml"), Filter::MinSize(5), Filter::MinAge(3_600_000)]);
+        let modified = Utc::now() - Duration::hours(2);
+
+        assert!(filter.stored_file_matches("/storage/red", "/storage/red/in/a.xml", 5, modified));
+        assert!(!filter.stored_file_matches("/storage/red", "/storage/red/in/a.xml", 4, modified));
+        assert!(!filter.stored_file_matches("/storage/red", "/storage/red/in/a.xml", 5, Utc::now()));
+        assert!(!filter.stored_file_matches("/storage", "/storage/red/in/a.xml", 5, modified));
+    }
+}
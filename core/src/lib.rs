@@ -12,12 +12,54 @@ extern crate error_chain;
 
 extern crate log;
 
+extern crate base64;
+
 use log::{info, error};
 
+pub mod filter;
 pub mod sftp_connection;
 
-pub use self::sftp_connection::SftpConnection;
+pub use self::sftp_connection::{HostKeyPolicy, SftpConnection};
+
+/// The SSH library of `SftpConnection`, for users that need its types
+pub use ssh2;
+
+
+/// Action on a remote file after it has been downloaded
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PostDownloadAction {
+    #[default]
+    None,
+    Delete,
+    /// Rename the file to the rendered template, relative to the directory of
+    /// the file, e.g. '{{ file_name }}.done'
+    Rename { template: String },
+    /// Move the file to a directory, relative to the directory of the file
+    /// when not absolute. The directory is created when it does not exist.
+    Move { directory: String },
+}
 
+/// What to do with the completion marker of a file once the file itself has
+/// been downloaded
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkerAction {
+    #[default]
+    Keep,
+    /// Download the marker next to the downloaded file
+    Download,
+    /// Remove the remote marker, recording a failure with the download
+    Delete,
+}
+
+/// Completion marker of a remote file, e.g. 'foo.xml.done' for 'foo.xml'
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
+pub struct Marker {
+    pub path: String,
+    #[serde(default)]
+    pub action: MarkerAction,
+}
 
 /// The set of commands that can be sent over the command queue
 #[derive(Debug, Deserialize, Clone, Serialize)]
@@ -27,7 +69,23 @@ pub struct SftpDownload {
     pub size: Option<u64>,
     pub sftp_source: String,
     pub path: String,
-    pub remove: bool
+    /// Kept for commands of scanners that do not send a post-download action
+    pub remove: bool,
+    #[serde(default)]
+    pub post_download: PostDownloadAction,
+    #[serde(default)]
+    pub marker: Option<Marker>,
+}
+
+impl SftpDownload {
+    /// The action to perform after the download, where the remove flag is
+    /// equivalent to a delete action.
+    pub fn post_download_action(&self) -> PostDownloadAction {
+        match &self.post_download {
+            PostDownloadAction::None if self.remove => PostDownloadAction::Delete,
+            action => action.clone()
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
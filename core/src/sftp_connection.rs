@@ -1,24 +1,40 @@
 use std::net::TcpStream;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time;
 
-use ssh2::{Session, Sftp};
+use ssh2::{CheckResult, HashType, KnownHostFileKind, Session, Sftp};
 
 use owning_ref::OwningHandle;
 
-use log::{info, debug, error};
+use log::{info, debug, error, warn};
 
 error_chain! {
     errors {
         DisconnectedError
         ConnectInterrupted
         AuthorizationError
+        HostKeyMismatch(host: String, fingerprint: String) {
+            description("host key mismatch")
+            display("host key of '{}' does not match the trusted key, offered key has fingerprint {}", host, fingerprint)
+        }
+        HostKeyUnknown(host: String, fingerprint: String) {
+            description("unknown host key")
+            display("host key of '{}' is not trusted, offered key has fingerprint {}", host, fingerprint)
+        }
+        HostKeyPolicyConflict(host: String) {
+            description("host key verification disabled while trusted keys are configured")
+            display("host key policy of '{}' is 'off', but known_hosts or host_key_fingerprints are configured", host)
+        }
     }
 }
 
+/// Serializes updates of known_hosts files when multiple connections learn a
+/// new host key at the same time.
+static KNOWN_HOSTS_LOCK: Mutex<()> = Mutex::new(());
+
 pub struct SftpConnection {
     pub sftp: OwningHandle<Box<Session>, Box<Sftp>>,
 }
@@ -29,12 +45,166 @@ pub struct SftpConfig {
     pub username: String,
     pub password: Option<String>,
     pub key_file: Option<PathBuf>,
-    pub compress: bool
+    pub compress: bool,
+    #[serde(default)]
+    pub known_hosts: Option<PathBuf>,
+    #[serde(default)]
+    pub host_key_fingerprints: Vec<String>,
+    #[serde(default)]
+    pub host_key_policy: HostKeyPolicy,
+}
+
+/// How the host key offered by the SSH server is checked against the
+/// configured known_hosts file and pinned fingerprints.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum HostKeyPolicy {
+    /// The host key must match a pinned fingerprint or a known_hosts entry
+    #[serde(rename = "strict")]
+    Strict,
+    /// Unknown hosts are trusted on first use and added to the known_hosts
+    /// file, known hosts must match
+    #[serde(rename = "accept-new")]
+    AcceptNew,
+    /// No host key verification at all
+    #[serde(rename = "off")]
+    #[default]
+    Off,
+}
+
+impl HostKeyPolicy {
+    /// Policy that applies when none is configured: strict when a known_hosts
+    /// file or pinned fingerprints are configured, off otherwise.
+    pub fn effective(configured: Option<HostKeyPolicy>, known_hosts: &Option<PathBuf>, fingerprints: &[String]) -> HostKeyPolicy {
+        match configured {
+            Some(policy) => policy,
+            None if known_hosts.is_some() || !fingerprints.is_empty() => HostKeyPolicy::Strict,
+            None => HostKeyPolicy::Off,
+        }
+    }
+
+    /// Reject an explicit 'off' policy next to a known_hosts file or pinned
+    /// fingerprints, which would otherwise be silently ignored.
+    pub fn validate(configured: Option<HostKeyPolicy>, known_hosts: &Option<PathBuf>, fingerprints: &[String]) -> std::result::Result<(), String> {
+        if configured == Some(HostKeyPolicy::Off) && (known_hosts.is_some() || !fingerprints.is_empty()) {
+            return Err("host_key_policy 'off' ignores the configured known_hosts and host_key_fingerprints".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the OpenSSH style SHA256 fingerprint of the session's host key,
+/// e.g. 'SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8'.
+fn host_key_fingerprint(session: &Session) -> Option<String> {
+    session.host_key_hash(HashType::Sha256).map(|hash| {
+        format!("SHA256:{}", base64::encode_config(hash, base64::STANDARD_NO_PAD))
+    })
+}
+
+/// Compare a configured fingerprint with a calculated one. The 'SHA256:'
+/// prefix and base64 padding are optional in the configured value.
+fn fingerprint_matches(configured: &str, fingerprint: &str) -> bool {
+    let configured = configured.trim().trim_end_matches('=');
+
+    let configured = configured.strip_prefix("SHA256:").unwrap_or(configured);
+
+    fingerprint.strip_prefix("SHA256:") == Some(configured)
+}
+
+/// Split an address in the form 'host:port' into host and port, defaulting
+/// to port 22 when no port is specified.
+fn split_address(address: &str) -> (&str, u16) {
+    match address.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(p) => (host.trim_start_matches('[').trim_end_matches(']'), p),
+            Err(_) => (address, 22),
+        },
+        None => (address, 22),
+    }
+}
+
+fn verify_host_key(session: &Session, config: &SftpConfig) -> Result<()> {
+    if config.host_key_policy == HostKeyPolicy::Off {
+        if config.known_hosts.is_some() || !config.host_key_fingerprints.is_empty() {
+            return Err(ErrorKind::HostKeyPolicyConflict(config.address.clone()).into());
+        }
+
+        warn!("Host key verification for {} is disabled", &config.address);
+        return Ok(());
+    }
+
+    let (key, key_type) = match session.host_key() {
+        Some(k) => k,
+        None => return Err("No host key offered by server".into()),
+    };
+
+    let fingerprint = host_key_fingerprint(session).unwrap_or_default();
+
+    if config.host_key_fingerprints.iter().any(|f| fingerprint_matches(f, &fingerprint)) {
+        debug!("Host key of {} matches pinned fingerprint", &config.address);
+        return Ok(());
+    }
+
+    let known_hosts_path = match &config.known_hosts {
+        Some(p) => p,
+        None => {
+            return Err(if config.host_key_fingerprints.is_empty() {
+                "Host key verification requires known_hosts or host_key_fingerprints".into()
+            } else {
+                ErrorKind::HostKeyMismatch(config.address.clone(), fingerprint).into()
+            })
+        }
+    };
+
+    let (host, port) = split_address(&config.address);
+
+    let _guard = KNOWN_HOSTS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut known_hosts = session.known_hosts().chain_err(|| "Error initializing known hosts")?;
+
+    if known_hosts_path.exists() {
+        known_hosts.read_file(known_hosts_path, KnownHostFileKind::OpenSSH)
+            .chain_err(|| format!("Error reading known hosts file '{}'", known_hosts_path.to_string_lossy()))?;
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => {
+            debug!("Host key of {} matches known hosts", &config.address);
+            Ok(())
+        },
+        CheckResult::Mismatch => Err(ErrorKind::HostKeyMismatch(config.address.clone(), fingerprint).into()),
+        // Pinned fingerprints take precedence over trust on first use
+        CheckResult::NotFound if !config.host_key_fingerprints.is_empty() => {
+            Err(ErrorKind::HostKeyMismatch(config.address.clone(), fingerprint).into())
+        },
+        CheckResult::NotFound if config.host_key_policy == HostKeyPolicy::AcceptNew => {
+            add_known_host(&mut known_hosts, known_hosts_path, host, port, key, key_type.into())?;
+
+            warn!("Added host key of {} to '{}': {}", &config.address, known_hosts_path.to_string_lossy(), &fingerprint);
+
+            Ok(())
+        },
+        CheckResult::NotFound => Err(ErrorKind::HostKeyUnknown(config.address.clone(), fingerprint).into()),
+        CheckResult::Failure => Err("Error checking host key against known hosts".into()),
+    }
+}
+
+fn add_known_host(known_hosts: &mut ssh2::KnownHosts, path: &Path, host: &str, port: u16, key: &[u8], key_format: ssh2::KnownHostKeyFormat) -> Result<()> {
+    let entry_name = match port {
+        22 => host.to_string(),
+        _ => format!("[{}]:{}", host, port),
+    };
+
+    known_hosts.add(&entry_name, key, "added by cortex", key_format)
+        .chain_err(|| format!("Error adding host key for '{}'", &entry_name))?;
+
+    known_hosts.write_file(path, KnownHostFileKind::OpenSSH)
+        .chain_err(|| format!("Error writing known hosts file '{}'", path.to_string_lossy()))
 }
 
 impl SftpConnection {
     pub fn connect(config: SftpConfig) -> Result<SftpConnection> {
-        let tcp_connect_result = TcpStream::connect(config.address);
+        let tcp_connect_result = TcpStream::connect(&config.address);
 
         let tcp = match tcp_connect_result {
             Ok(v) => v,
@@ -58,6 +228,9 @@ impl SftpConnection {
             }
         }
 
+        // Verify the server before sending any credentials
+        verify_host_key(&session, &config)?;
+
         let auth_result = match config.key_file {
             Some(key_file_path) => {
                 info!("Authorizing using key {}", &key_file_path.to_string_lossy());
@@ -92,7 +265,15 @@ impl SftpConnection {
 
             match conn_result {
                 Ok(c) => return Ok(c),
-                Err(e) => error!("Could not connect: {}", e),
+                Err(e) => match e.kind() {
+                    // Retrying will not change the host key, so report it
+                    // to the caller instead of looping forever.
+                    ErrorKind::HostKeyMismatch(_, _) | ErrorKind::HostKeyUnknown(_, _) | ErrorKind::HostKeyPolicyConflict(_) => {
+                        error!("[E01010] Host key verification failed: {}", e);
+                        return Err(e)
+                    },
+                    _ => error!("Could not connect: {}", e),
+                }
             }
 
             thread::sleep(time::Duration::from_millis(1000));
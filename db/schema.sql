@@ -27,10 +27,15 @@ CREATE TABLE "dispatcher"."file"
   "modified" timestamptz NOT NULL,
   "size" bigint NOT NULL,
   "hash" text,
+  "checksum" text,
+  "checksum_verification" text,
   PRIMARY KEY (id)
 );
 
-COMMENT ON TABLE "dispatcher"."file" IS 'All files in the internal storage area of Cortex are registered here.';
+COMMENT ON TABLE "dispatcher"."file" IS 'All files in the internal storage area of Cortex are registered here.
+The checksum from the sidecar file of the remote file is stored as
+''<algorithm>:<hex digest>'', with the outcome of the verification:
+''verified'', or ''mismatch'' for quarantined files.';
 
 CREATE INDEX "sftp_download_file_index" ON "dispatcher"."file" USING btree (source, path);
 
@@ -44,13 +49,40 @@ CREATE TABLE "dispatcher"."sftp_download"
   "path" text NOT NULL,
   "size" bigint,
   "file_id" bigint,
+  "attempts" integer NOT NULL DEFAULT 0,
+  "last_error" text,
+  "state" text NOT NULL DEFAULT 'queued'::text,
+  "state_changed" timestamptz NOT NULL DEFAULT now(),
+  "download_started" timestamptz,
+  "post_download" text,
+  "post_download_error" text,
+  "marker" text,
+  "marker_error" text,
   PRIMARY KEY (id)
 );
 
-COMMENT ON TABLE "dispatcher"."sftp_download" IS 'Contains records of files that need to be downloaded from a remote SFTP location.';
+COMMENT ON TABLE "dispatcher"."sftp_download" IS 'Contains records of files that need to be downloaded from a remote SFTP location.
+Records are kept after the download to document what happened to each
+file, with a state of ''queued'', ''downloading'', ''downloaded'', ''failed'' or
+''vanished'' when the remote file no longer existed.';
 
 CREATE INDEX "sftp_download_file_index" ON "dispatcher"."sftp_download" USING btree (source, path);
 
+CREATE INDEX "sftp_download_state_index" ON "dispatcher"."sftp_download" USING btree (source, state);
+
+
+
+CREATE TABLE "dispatcher"."deferred_action"
+(
+  "sftp_download_id" bigint NOT NULL,
+  "timestamp" timestamptz NOT NULL DEFAULT now(),
+  "action" text NOT NULL,
+  PRIMARY KEY (sftp_download_id)
+);
+
+COMMENT ON TABLE "dispatcher"."deferred_action" IS 'Post-download actions on remote files, such as removal, that wait until
+the downloaded file has been dispatched to all targets of its source.';
+
 
 
 CREATE TABLE "dispatcher"."directory_source"
@@ -82,17 +114,38 @@ CREATE TABLE "dispatcher"."dispatched"
 
 
 
+CREATE TABLE "dispatcher"."delivery_attempt"
+(
+  "file_id" bigint NOT NULL,
+  "target" text NOT NULL,
+  "timestamp" timestamptz NOT NULL DEFAULT now(),
+  "attempts" integer NOT NULL DEFAULT 0,
+  "state" text NOT NULL DEFAULT 'pending'::text,
+  "next_attempt" timestamptz NOT NULL DEFAULT now(),
+  "last_error" text,
+  PRIMARY KEY (file_id, target)
+);
+
+COMMENT ON TABLE "dispatcher"."delivery_attempt" IS 'Failed deliveries of files to targets. Pending deliveries are retried
+with exponential backoff until they succeed, after which the record is
+removed, or until the maximum number of attempts is reached, after which
+the state is set to ''dead''.';
+
+CREATE INDEX "delivery_attempt_next_attempt_index" ON "dispatcher"."delivery_attempt" USING btree (state, next_attempt);
+
+
+
 CREATE FUNCTION "dispatcher"."undispatched_files"("source" text, "target" text, timestamptz)
     RETURNS SETOF bigint
 AS $$
 SELECT file.id
 FROM dispatcher.file LEFT JOIN dispatcher.dispatched
 ON dispatched.file_id = file.id
-  AND file.source = $1
   AND dispatched.target = $2
 WHERE dispatched IS NULL
+  AND file.source = $1
   AND file.timestamp < $3;
-$$ LANGUAGE sql IMMUTABLE;
+$$ LANGUAGE sql STABLE;
 
 COMMENT ON FUNCTION "dispatcher"."undispatched_files"("source" text, "target" text, timestamptz) IS 'Provide the ids of files from source that have not been sent to
 the target yet. Only files inserted before the given timestamp
@@ -103,7 +156,7 @@ CREATE FUNCTION "dispatcher"."undispatched_files"("source" text, "target" text,
     RETURNS SETOF bigint
 AS $$
 SELECT dispatcher.undispatched_files($1, $2, now()-$3);
-$$ LANGUAGE sql IMMUTABLE;
+$$ LANGUAGE sql STABLE;
 
 COMMENT ON FUNCTION "dispatcher"."undispatched_files"("source" text, "target" text, interval) IS 'Provide the ids of files from source that have not been sent to
 the target yet. Only files that have been in the database for
@@ -114,7 +167,7 @@ CREATE FUNCTION "dispatcher"."undispatched_files"("source" text, "target" text)
     RETURNS SETOF bigint
 AS $$
 SELECT dispatcher.undispatched_files($1, $2, now());
-$$ LANGUAGE sql IMMUTABLE;
+$$ LANGUAGE sql STABLE;
 
 COMMENT ON FUNCTION "dispatcher"."undispatched_files"("source" text, "target" text) IS 'Provide the ids of files from source that have not been sent to
 the target yet.';
@@ -125,6 +178,11 @@ ALTER TABLE "dispatcher"."sftp_download"
   FOREIGN KEY (file_id)
   REFERENCES "dispatcher"."file" (id) ON DELETE CASCADE;
 
+ALTER TABLE "dispatcher"."deferred_action"
+  ADD CONSTRAINT "deferred_action_sftp_download_id_fkey"
+  FOREIGN KEY (sftp_download_id)
+  REFERENCES "dispatcher"."sftp_download" (id) ON DELETE CASCADE;
+
 ALTER TABLE "dispatcher"."directory_source"
   ADD CONSTRAINT "directory_source_file_id_fkey"
   FOREIGN KEY (file_id)
@@ -134,3 +192,8 @@ ALTER TABLE "dispatcher"."dispatched"
   ADD CONSTRAINT "dispatched_file_id_fkey"
   FOREIGN KEY (file_id)
   REFERENCES "dispatcher"."file" (id) ON DELETE CASCADE;
+
+ALTER TABLE "dispatcher"."delivery_attempt"
+  ADD CONSTRAINT "delivery_attempt_file_id_fkey"
+  FOREIGN KEY (file_id)
+  REFERENCES "dispatcher"."file" (id) ON DELETE CASCADE;
@@ -0,0 +1,150 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use postgres::tls::{MakeTlsConnect, TlsConnect};
+use tokio_postgres::Socket;
+
+use crate::base_types::Connection;
+use crate::event::FileEvent;
+use crate::metrics;
+use crate::persistence::PostgresAsyncPersistence;
+use crate::settings;
+
+/// Files sent to a target by the backfill, by source and target name, so
+/// that later runs do not send a file again while its delivery is still in
+/// progress. Files that are no longer undispatched, because they were
+/// delivered or their delivery failed and is retried, are forgotten.
+pub type Enqueued = Mutex<HashMap<(String, String), HashSet<i64>>>;
+
+/// Send file events to the targets of all connections for files that were
+/// stored, but never dispatched to that target.
+pub async fn backfill_connections<T>(
+    persistence: &PostgresAsyncPersistence<T>,
+    connections: &[Connection],
+    storage_directory: &Path,
+    grace_interval: u64,
+    enqueued: &Enqueued,
+)
+where
+    T: MakeTlsConnect<Socket> + Clone + 'static + Sync + Send,
+    T::TlsConnect: Send,
+    T::Stream: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    for connection in connections {
+        let query_result = persistence.undispatched_files(&connection.source_name, &connection.target.name, grace_interval).await;
+
+        let undispatched = match query_result {
+            Ok(files) => files,
+            Err(e) => {
+                error!("Could not query undispatched files of '{}' for '{}': {}", &connection.source_name, &connection.target.name, e);
+                continue;
+            }
+        };
+
+        let mut count: u64 = 0;
+        let source_directory = storage_directory.join(&connection.source_name);
+
+        let mut enqueued = enqueued.lock().unwrap();
+        let sent = enqueued.entry((connection.source_name.clone(), connection.target.name.clone())).or_default();
+
+        let undispatched_ids: HashSet<i64> = undispatched.iter().map(|file| file.file_id).collect();
+        sent.retain(|file_id| undispatched_ids.contains(file_id));
+
+        for file in undispatched {
+            if sent.contains(&file.file_id) {
+                continue;
+            }
+
+            let file_matches = match &connection.filter {
+                Some(f) => f.stored_file_matches(&source_directory, &file.path, file.size as u64, file.modified),
+                None => true
+            };
+
+            if !file_matches {
+                continue;
+            }
+
+            let file_id = file.file_id;
+
+            let file_event = FileEvent {
+                file_id,
+                source_name: connection.source_name.clone(),
+                path: file.path,
+            };
+
+            match connection.target.sender.send(file_event) {
+                Ok(_) => {
+                    sent.insert(file_id);
+                    count += 1;
+                },
+                Err(e) => {
+                    error!("Could not send backfill event to target handler '{}': {}", &connection.target.name, e);
+                    break;
+                }
+            }
+        }
+
+        if count > 0 {
+            info!("Backfilled {} files from '{}' to '{}'", count, &connection.source_name, &connection.target.name);
+
+            metrics::BACKFILLED_FILES_COUNTER
+                .with_label_values(&[&connection.target.name])
+                .inc_by(count);
+        }
+    }
+}
+
+/// Run the backfill at startup, followed by one more run after the grace
+/// interval, and/or at the configured interval.
+pub async fn run_backfill<T>(
+    persistence: PostgresAsyncPersistence<T>,
+    connections: Vec<Connection>,
+    storage_directory: PathBuf,
+    settings: settings::Backfill,
+)
+where
+    T: MakeTlsConnect<Socket> + Clone + 'static + Sync + Send,
+    T::TlsConnect: Send,
+    T::Stream: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    let enqueued: Arc<Enqueued> = Arc::new(Enqueued::default());
+
+    if settings.on_startup {
+        info!("Starting backfill of undispatched files");
+
+        backfill_connections(&persistence, &connections, &storage_directory, settings.grace_interval, &enqueued).await;
+
+        // Files stored shortly before the restart were still within the grace
+        // interval, so they are picked up by one more run once it has passed.
+        let follow_up_persistence = persistence.clone();
+        let follow_up_connections = connections.clone();
+        let follow_up_storage_directory = storage_directory.clone();
+        let follow_up_enqueued = enqueued.clone();
+        let grace_interval = settings.grace_interval;
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(grace_interval)).await;
+
+            info!("Starting follow-up backfill of undispatched files");
+
+            backfill_connections(&follow_up_persistence, &follow_up_connections, &follow_up_storage_directory, grace_interval, &follow_up_enqueued).await;
+        });
+    }
+
+    if let Some(interval) = settings.interval {
+        let period = Duration::from_millis(interval);
+        let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        loop {
+            timer.tick().await;
+
+            debug!("Starting periodic backfill of undispatched files");
+
+            backfill_connections(&persistence, &connections, &storage_directory, settings.grace_interval, &enqueued).await;
+        }
+    }
+}
@@ -1,57 +1,356 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::sync::{Arc, Mutex};
 
+use chrono::Utc;
 use tera::{Context, Tera};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_postgres::Socket;
+use postgres::tls::{MakeTlsConnect, TlsConnect};
 
 use crate::event::FileEvent;
+use crate::metrics;
+use crate::persistence::{FileDetails, PostgresAsyncPersistence};
 use crate::settings;
-use lapin::options::BasicPublishOptions;
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use lapin::publisher_confirm::Confirmation;
+use lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
 use lapin::{BasicProperties, Channel};
 
+const NOTIFICATION_TEMPLATE_NAME: &str = "notification";
+
+/// AMQP delivery mode of messages that survive a broker restart
+const PERSISTENT_DELIVERY_MODE: u8 = 2;
+
+/// Compile a notification message template and optional header value
+/// templates, so that errors in the templates are found at startup instead of
+/// for every file.
+pub fn compile_templates(message_template: &str, header_templates: &HashMap<String, String>) -> Result<Tera, String> {
+    let mut tera = Tera::default();
+
+    tera.add_raw_template(NOTIFICATION_TEMPLATE_NAME, message_template)
+        .map_err(|e| format!("Error compiling notification template: {}", e))?;
+
+    for (name, value_template) in header_templates {
+        tera.add_raw_template(&header_template_name(name), value_template)
+            .map_err(|e| format!("Error compiling template of header '{}': {}", name, e))?;
+    }
+
+    Ok(tera)
+}
+
+fn header_template_name(header_name: &str) -> String {
+    format!("header.{}", header_name)
+}
+
+/// Build the context available to notification templates:
+///
+/// - file_path: path of the file at the target
+/// - storage_path: path of the file in the internal storage
+/// - file_id: id of the file in the dispatcher.file table
+/// - source_name: name of the source the file came from
+/// - target_name: name of the target the file was dispatched to
+/// - size: size of the file in bytes
+/// - sha256: SHA-256 hash of the file contents
+/// - modified: modification time of the file (RFC 3339)
+/// - dispatched: time of dispatch to the target (RFC 3339)
+/// - remote_path: original path on the remote SFTP server
+///
+/// The values that come from the file record are absent when it could not be
+/// read.
+fn notification_context(target_name: &str, file_event: &FileEvent, details: Option<&FileDetails>) -> Context {
+    let mut context = Context::new();
+    context.insert("file_path", &file_event.path);
+    context.insert("file_id", &file_event.file_id);
+    context.insert("target_name", target_name);
+    context.insert("dispatched", &Utc::now().to_rfc3339());
+
+    if let Some(d) = details {
+        context.insert("source_name", &d.source);
+        context.insert("storage_path", &d.path);
+        context.insert("size", &d.size);
+        context.insert("sha256", &d.hash);
+        context.insert("modified", &d.modified.to_rfc3339());
+        context.insert("remote_path", &d.remote_path);
+    }
+
+    context
+}
+
+/// Connect to an AMQP service and create a channel with publisher confirms
+/// enabled
+async fn open_channel(address: &str) -> Result<Channel, lapin::Error> {
+    let connection = lapin::Connection::connect(
+        address,
+        lapin::ConnectionProperties::default(),
+    ).await?;
+
+    let channel = connection.create_channel().await?;
+
+    channel.confirm_select(ConfirmSelectOptions::default()).await?;
+
+    Ok(channel)
+}
 
 pub struct RabbitMQNotify {
-    pub message_template: String,
+    pub address: String,
+    /// Replaced when the channel is found closed
+    channel: tokio::sync::Mutex<Channel>,
+    pub tera: Tera,
     pub exchange: String,
     pub routing_key: String,
+    pub content_type: String,
+    pub header_names: Vec<String>,
+    pub retries: u32,
+    pub retry_delay: std::time::Duration,
 }
 
 impl RabbitMQNotify {
-    pub async fn notify(&self, channel: &Channel, file_event: FileEvent) {
-        let template_name = "notification";
-        let exchange = self.exchange.clone();
-        let routing_key = self.routing_key.clone();
+    /// Connect to the AMQP service of the notification settings and create the
+    /// channel to publish notifications on, with publisher confirms enabled.
+    pub async fn connect(notify_conf: &settings::RabbitMQNotify, tera: Tera) -> Result<RabbitMQNotify, lapin::Error> {
+        let channel = open_channel(&notify_conf.address).await?;
+
+        let notify = RabbitMQNotify {
+            address: notify_conf.address.clone(),
+            channel: tokio::sync::Mutex::new(channel),
+            tera,
+            exchange: notify_conf.exchange.clone(),
+            routing_key: notify_conf.routing_key.clone(),
+            content_type: notify_conf.content_type.clone(),
+            header_names: notify_conf.headers.keys().cloned().collect(),
+            retries: notify_conf.retries,
+            retry_delay: std::time::Duration::from_millis(notify_conf.retry_delay),
+        };
+
+        Ok(notify)
+    }
+
+    fn properties(&self, context: &Context) -> Result<BasicProperties, String> {
+        let mut headers = FieldTable::default();
+
+        for name in &self.header_names {
+            let value = self.tera.render(&header_template_name(name), context)
+                .map_err(|e| format!("Error rendering template of header '{}': {}", name, e))?;
 
-        let mut tera = Tera::default();
+            headers.insert(ShortString::from(name.as_str()), AMQPValue::LongString(LongString::from(value)));
+        }
+
+        Ok(BasicProperties::default()
+            .with_content_type(ShortString::from(self.content_type.as_str()))
+            .with_delivery_mode(PERSISTENT_DELIVERY_MODE)
+            .with_headers(headers))
+    }
+
+    /// Publish a message and wait for the broker to confirm it
+    async fn publish(&self, channel: &Channel, message: &[u8], properties: BasicProperties) -> Result<(), String> {
+        let confirm = channel.basic_publish(
+            &self.exchange,
+            &self.routing_key,
+            BasicPublishOptions::default(),
+            message.to_vec(),
+            properties
+        ).await.map_err(|e| format!("Error publishing notification: {}", e))?;
+
+        let confirmation = confirm.await
+            .map_err(|e| format!("Error waiting for publisher confirm: {}", e))?;
+
+        match confirmation {
+            Confirmation::Ack(_) => Ok(()),
+            Confirmation::Nack(_) => Err(String::from("Notification was rejected by the broker")),
+            Confirmation::NotRequested => Err(String::from("Notification was not confirmed by the broker")),
+        }
+    }
+
+    /// The channel to publish on, which is replaced by a new connection and
+    /// channel when it was closed, e.g. after a broker restart.
+    async fn usable_channel(&self) -> Result<Channel, String> {
+        let mut guard = self.channel.lock().await;
 
-        if let Err(e) = tera.add_raw_template(template_name, &self.message_template) {
-            error!("Error adding template: {}", e);
+        if !guard.status().connected() {
+            info!("Notifier channel to {} is closed, reconnecting", &self.address);
+
+            *guard = open_channel(&self.address).await
+                .map_err(|e| format!("Error reconnecting notifier to AMQP service: {}", e))?;
         }
 
-        let mut context = Context::new();
-        context.insert("file_path", &file_event.path);
+        Ok(guard.clone())
+    }
+
+    pub async fn notify(&self, context: &Context) -> Result<(), String> {
+        let message_str = self.tera.render(NOTIFICATION_TEMPLATE_NAME, context)
+            .map_err(|e| format!("Error rendering template: {}", e))?;
 
-        let render_result = tera.render(template_name, &context);
+        let properties = self.properties(context)?;
 
-        match render_result {
-            Ok(message_str) => {
-                let publish_result = channel.basic_publish(
-                    &exchange,
-                    &routing_key,
-                    BasicPublishOptions::default(),
-                    message_str.as_bytes().to_vec(),
-                    BasicProperties::default()
-                ).await;
+        let mut attempt = 0;
 
-                match publish_result {
-                    Ok(_) => debug!("published"),
-                    Err(e) => error!("Error publishing notification: {}", e)
+        loop {
+            let publish_result = match self.usable_channel().await {
+                Ok(c) => self.publish(&c, message_str.as_bytes(), properties.clone()).await,
+                Err(e) => Err(e),
+            };
+
+            match publish_result {
+                Ok(_) => {
+                    debug!("published");
+                    return Ok(())
+                },
+                Err(e) => {
+                    if attempt >= self.retries {
+                        return Err(e);
+                    }
+
+                    attempt += 1;
+
+                    warn!("Error publishing notification, retry {} of {}: {}", attempt, self.retries, e);
+
+                    tokio::time::sleep(self.retry_delay).await;
                 }
+            }
+        }
+    }
+}
+
+/// Notification of consumers by an HTTP POST request
+pub struct WebhookNotify {
+    pub url: String,
+    pub tera: Tera,
+    pub client: reqwest::Client,
+    pub retries: u32,
+    pub retry_delay: std::time::Duration,
+}
+
+impl WebhookNotify {
+    pub fn new(notify_conf: &settings::WebhookNotify) -> Result<WebhookNotify, String> {
+        let tera = compile_templates(&notify_conf.message_template, &HashMap::new())?;
+
+        let headers = reqwest::header::HeaderMap::try_from(&notify_conf.headers)
+            .map_err(|e| format!("Invalid webhook header: {}", e))?;
+
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_millis(notify_conf.timeout))
+            .default_headers(headers)
+            .build()
+            .map_err(|e| format!("Error creating HTTP client: {}", e))?;
+
+        Ok(WebhookNotify {
+            url: notify_conf.url.clone(),
+            tera,
+            client,
+            retries: notify_conf.retries,
+            retry_delay: std::time::Duration::from_millis(notify_conf.retry_delay),
+        })
+    }
+
+    async fn post(&self, body: &str) -> Result<(), String> {
+        let response = self.client.post(&self.url)
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("unexpected HTTP status {}", response.status()))
+        }
+    }
+
+    pub async fn notify(&self, context: &Context) -> Result<(), String> {
+        let message_str = self.tera.render(NOTIFICATION_TEMPLATE_NAME, context)
+            .map_err(|e| format!("Error rendering template: {}", e))?;
+
+        let mut attempt = 0;
+
+        loop {
+            match self.post(&message_str).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    if attempt >= self.retries {
+                        return Err(format!("Error posting notification to '{}': {}", &self.url, e));
+                    }
+
+                    attempt += 1;
+
+                    warn!("Error posting notification to '{}', retry {} of {}: {}", &self.url, attempt, self.retries, e);
+
+                    tokio::time::sleep(self.retry_delay).await;
+                }
+            }
+        }
+    }
+}
+
+/// Notifier of consumers of a target about files placed at the target
+pub enum Notifier {
+    RabbitMQ(Box<RabbitMQNotify>),
+    Webhook(Box<WebhookNotify>),
+}
+
+impl Notifier {
+    pub async fn connect(notify_conf: &settings::Notify) -> Result<Notifier, String> {
+        match notify_conf {
+            settings::Notify::RabbitMQ(conf) => {
+                let tera = compile_templates(&conf.message_template, &conf.headers)?;
+
+                let notify = RabbitMQNotify::connect(conf, tera).await
+                    .map_err(|e| format!("Error connecting notifier to AMQP service: {}", e))?;
+
+                Ok(Notifier::RabbitMQ(Box::new(notify)))
             },
-            Err(e) => {
-                error!("Error rendering template: {}", e);
+            settings::Notify::Webhook(conf) => {
+                Ok(Notifier::Webhook(Box::new(WebhookNotify::new(conf)?)))
             }
         }
     }
+
+    /// Notify about a file placed at a target and count the outcome. A failed
+    /// notification is returned, so that the delivery can be retried.
+    pub async fn notify<T>(&self, target_name: &str, file_event: FileEvent, persistence: &PostgresAsyncPersistence<T>) -> Result<(), String>
+    where
+        T: MakeTlsConnect<Socket> + Clone + 'static + Sync + Send,
+        T::TlsConnect: Send,
+        T::Stream: Send + Sync,
+        <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        let details = match persistence.file_details(file_event.file_id).await {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("Could not read details of file {} for notification: {}", file_event.file_id, e);
+                None
+            }
+        };
+
+        let context = notification_context(target_name, &file_event, details.as_ref());
+
+        let (kind, result) = match self {
+            Notifier::RabbitMQ(notify) => {
+                debug!("Notifying with AMQP routing key {}", &notify.routing_key);
+
+                ("rabbitmq", notify.notify(&context).await)
+            },
+            Notifier::Webhook(notify) => {
+                debug!("Notifying with webhook {}", &notify.url);
+
+                ("webhook", notify.notify(&context).await)
+            }
+        };
+
+        let outcome = match &result {
+            Ok(_) => "success",
+            Err(e) => {
+                warn!("[E01016] Error notifying about file for target '{}': {}", target_name, e);
+                "failure"
+            }
+        };
+
+        metrics::NOTIFICATIONS_COUNTER
+            .with_label_values(&[target_name, kind, outcome])
+            .inc();
+
+        result.map_err(|e| format!("Notification failed: {}", e))
+    }
 }
 
 #[derive(Debug)]
@@ -83,5 +382,31 @@ pub struct CortexConfig {
 #[derive(Debug, Clone)]
 pub enum MessageResponse {
     Ack { delivery_tag: u64 },
-    Nack { delivery_tag: u64 }
+    /// The message could not be processed, with the error chain as reason and
+    /// the number of attempts when the source keeps track of them
+    Nack { delivery_tag: u64, error: String, attempts: Option<i32> },
+    /// Put the message back on the queue, e.g. when it was not processed
+    /// because of a shutdown
+    Requeue { delivery_tag: u64 },
+}
+
+/// Connection state of the AMQP command consumers per source, exposed in the
+/// health endpoint and as metric
+#[derive(Debug, Clone, Default)]
+pub struct AmqpHealth {
+    states: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl AmqpHealth {
+    pub fn set(&self, source_name: &str, connected: bool) {
+        self.states.lock().unwrap().insert(source_name.to_string(), connected);
+
+        metrics::AMQP_CONNECTED_GAUGE
+            .with_label_values(&[source_name])
+            .set(if connected { 1 } else { 0 });
+    }
+
+    pub fn states(&self) -> HashMap<String, bool> {
+        self.states.lock().unwrap().clone()
+    }
 }
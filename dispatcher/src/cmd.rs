@@ -1,4 +1,4 @@
-use clap::{crate_authors, crate_description, crate_version, App, Arg};
+use clap::{crate_authors, crate_description, crate_version, App, Arg, SubCommand};
 
 pub fn app() -> App<'static, 'static> {
     App::new("Cortex")
@@ -23,4 +23,23 @@ pub fn app() -> App<'static, 'static> {
                 .long("service")
                 .help("Run in service mode"),
         )
+        .subcommand(
+            SubCommand::with_name("republish-dead-letters")
+                .about("Move dead-lettered download commands back to the command queue of a source")
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .value_name("SOURCE")
+                        .help("Name of the source")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("COUNT")
+                        .help("Maximum number of commands to re-publish")
+                        .takes_value(true),
+                ),
+        )
 }
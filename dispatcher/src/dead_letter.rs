@@ -0,0 +1,218 @@
+use failure::{Error, err_msg};
+
+use lapin::options::{
+    BasicAckOptions, BasicGetOptions, BasicPublishOptions, ConfirmSelectOptions, ExchangeDeclareOptions,
+    QueueBindOptions, QueueDeclareOptions,
+};
+use lapin::publisher_confirm::Confirmation;
+use lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+use lapin::{BasicProperties, ConnectionProperties, ExchangeKind};
+
+use cortex_core::SftpDownload;
+
+use crate::metrics;
+use crate::persistence::PostgresAsyncPersistence;
+use crate::settings;
+
+/// Header with the error chain of the last failed attempt
+pub const ERROR_HEADER: &str = "x-cortex-error";
+
+/// Header with the number of failed attempts, as recorded for the download
+/// when the source keeps track of attempts, and otherwise counted per
+/// dead-lettering
+pub const ATTEMPTS_HEADER: &str = "x-cortex-attempts";
+
+/// Exchange the download commands of sources are published to
+const COMMAND_EXCHANGE: &str = "amq.direct";
+
+/// AMQP delivery mode of messages that survive a broker restart
+const PERSISTENT_DELIVERY_MODE: u8 = 2;
+
+/// Routing key of the download commands of a source
+fn routing_key(source_name: &str) -> String {
+    format!("source.{}", source_name)
+}
+
+/// Routing key of the failed commands of a source, which differs from that of
+/// the commands, so that an exchange shared with the commands, like
+/// amq.direct, does not route failed commands back to the command queue
+fn dead_letter_routing_key(source_name: &str) -> String {
+    format!("source.{}.dead-letter", source_name)
+}
+
+/// Declare the dead-letter exchange and queue of a source and bind them.
+pub async fn declare(channel: &lapin::Channel, dead_letter: &settings::DeadLetter, source_name: &str) -> Result<(), lapin::Error> {
+    let queue_name = dead_letter.queue_name(source_name);
+    let routing_key = dead_letter_routing_key(source_name);
+
+    channel.exchange_declare(
+        &dead_letter.exchange,
+        ExchangeKind::Direct,
+        ExchangeDeclareOptions { durable: true, ..ExchangeDeclareOptions::default() },
+        FieldTable::default(),
+    ).await?;
+
+    channel.queue_declare(
+        &queue_name,
+        QueueDeclareOptions { durable: true, ..QueueDeclareOptions::default() },
+        FieldTable::default(),
+    ).await?;
+
+    channel.queue_bind(
+        &queue_name,
+        &dead_letter.exchange,
+        &routing_key,
+        QueueBindOptions::default(),
+        FieldTable::default(),
+    ).await?;
+
+    debug!("Dead-letter queue '{}' bound to exchange '{}' for routing key '{}'", &queue_name, &dead_letter.exchange, &routing_key);
+
+    Ok(())
+}
+
+/// Number of failed attempts recorded in the headers of a command
+pub fn attempts(headers: &FieldTable) -> i64 {
+    match headers.inner().get(ATTEMPTS_HEADER) {
+        Some(AMQPValue::LongLongInt(n)) => *n,
+        Some(AMQPValue::LongInt(n)) => i64::from(*n),
+        Some(AMQPValue::ShortInt(n)) => i64::from(*n),
+        _ => 0
+    }
+}
+
+/// Publish a failed command to the dead-letter exchange of its source, with
+/// the error and the attempt count in the headers: the `attempts` made for
+/// the download when known, otherwise that in the headers plus one. Publisher
+/// confirms must be enabled on the channel, the command is only dead-lettered
+/// when the broker has confirmed it.
+pub async fn publish(
+    channel: &lapin::Channel,
+    dead_letter: &settings::DeadLetter,
+    source_name: &str,
+    data: Vec<u8>,
+    headers: FieldTable,
+    error: &str,
+    attempts: Option<i32>,
+) -> Result<(), String> {
+    let attempts = match attempts {
+        Some(n) => i64::from(n),
+        None => self::attempts(&headers) + 1
+    };
+
+    let mut headers = headers;
+    headers.insert(ShortString::from(ERROR_HEADER), AMQPValue::LongString(LongString::from(error)));
+    headers.insert(ShortString::from(ATTEMPTS_HEADER), AMQPValue::LongLongInt(attempts));
+
+    let properties = BasicProperties::default()
+        .with_delivery_mode(PERSISTENT_DELIVERY_MODE)
+        .with_headers(headers);
+
+    let confirmation = channel.basic_publish(
+        &dead_letter.exchange,
+        &dead_letter_routing_key(source_name),
+        BasicPublishOptions::default(),
+        data,
+        properties,
+    ).await
+        .map_err(|e| format!("Error publishing dead-lettered command: {}", e))?
+        .await
+        .map_err(|e| format!("Error waiting for publisher confirm: {}", e))?;
+
+    match confirmation {
+        Confirmation::Ack(_) => (),
+        Confirmation::Nack(_) => return Err(String::from("Dead-lettered command was rejected by the broker")),
+        Confirmation::NotRequested => return Err(String::from("Dead-lettered command was not confirmed by the broker")),
+    }
+
+    warn!("Dead-lettered command of source '{}' after {} attempts: {}", source_name, attempts, error);
+
+    metrics::DEAD_LETTERED_COUNTER
+        .with_label_values(&[source_name])
+        .inc();
+
+    Ok(())
+}
+
+/// Move the dead-lettered commands of a source back to its command queue, at
+/// most `limit` when specified. The attempt count in the headers is kept, the
+/// error is removed. The download records of re-published SFTP commands are
+/// queued again, with their attempt count reset. Returns the number of
+/// commands that were re-published.
+pub fn republish(settings: &settings::Settings, source_name: &str, limit: Option<u64>) -> Result<u64, Error> {
+    let is_sftp_source = settings.sftp_sources.iter().any(|s| s.name == source_name);
+
+    let dead_letter = settings.sftp_sources.iter()
+        .find(|s| s.name == source_name)
+        .map(|s| s.dead_letter.clone())
+        .or_else(|| settings.http_sources.iter().find(|s| s.name == source_name).map(|s| s.dead_letter.clone()))
+        .ok_or_else(|| err_msg(format!("No source '{}' configured", source_name)))?
+        .ok_or_else(|| err_msg(format!("No dead-letter handling configured for source '{}'", source_name)))?;
+
+    let queue_name = dead_letter.queue_name(source_name);
+
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    runtime.block_on(async {
+        let connection_manager = bb8_postgres::PostgresConnectionManager::new(settings.postgresql.url.parse()?, tokio_postgres::NoTls);
+        let persistence = PostgresAsyncPersistence::new(connection_manager).await;
+
+        let connection = lapin::Connection::connect(&settings.command_queue.address, ConnectionProperties::default()).await?;
+        let channel = connection.create_channel().await?;
+
+        channel.confirm_select(ConfirmSelectOptions::default()).await?;
+
+        let limit = limit.unwrap_or(u64::MAX);
+        let mut count: u64 = 0;
+
+        while count < limit {
+            let message = match channel.basic_get(&queue_name, BasicGetOptions::default()).await? {
+                Some(m) => m,
+                None => break
+            };
+
+            let delivery = message.delivery;
+
+            if is_sftp_source {
+                match serde_json::from_slice::<SftpDownload>(&delivery.data) {
+                    Ok(command) => persistence.requeue_sftp_download(command.id).await?,
+                    Err(e) => warn!("Could not read dead-lettered command of source '{}': {}", source_name, e),
+                }
+            }
+
+            let mut headers = FieldTable::default();
+
+            if let Some(h) = delivery.properties.headers() {
+                for (key, value) in h.inner() {
+                    if key.as_str() != ERROR_HEADER {
+                        headers.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+
+            let properties = BasicProperties::default()
+                .with_delivery_mode(PERSISTENT_DELIVERY_MODE)
+                .with_headers(headers);
+
+            let confirmation = channel.basic_publish(
+                COMMAND_EXCHANGE,
+                &routing_key(source_name),
+                BasicPublishOptions::default(),
+                delivery.data.clone(),
+                properties,
+            ).await?.await?;
+
+            if let Confirmation::Nack(_) = confirmation {
+                return Err(err_msg(format!("Re-published command was rejected by the broker, {} commands re-published", count)));
+            }
+
+            channel.basic_ack(delivery.delivery_tag, BasicAckOptions::default()).await?;
+
+            count += 1;
+        }
+
+        connection.close(200, "Done").await?;
+
+        Ok(count)
+    })
+}
@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use cortex_core::sftp_connection::SftpConnection;
+use cortex_core::PostDownloadAction;
+
+use crate::metrics;
+use crate::persistence::{DeferredAction, Persistence};
+use crate::post_download;
+use crate::settings;
+use crate::sftp_downloader::{sftp_config, sleep_unless_stopped};
+
+/// Periodically perform the deferred post-download actions of a source whose
+/// files have been dispatched to the targets of all matching connections, and
+/// report the actions that have been pending for too long.
+pub fn start<T>(
+    stop: Arc<AtomicBool>,
+    sftp_source: settings::SftpSource,
+    deferral: settings::DeferredActions,
+    connections: Vec<settings::Connection>,
+    storage_directory: PathBuf,
+    persistence: T,
+) -> thread::JoinHandle<()>
+where
+    T: Persistence + Send + 'static,
+{
+    thread::spawn(move || {
+        proctitle::set_title("sftp_deferred");
+
+        let connections: Vec<settings::Connection> = connections.into_iter()
+            .filter(|c| c.source == sftp_source.name)
+            .collect();
+
+        let source_directory = storage_directory.join(&sftp_source.name);
+
+        let mut sftp_connection: Option<SftpConnection> = None;
+
+        // Stale actions are logged once, but counted in every check
+        let mut reported: HashSet<i64> = HashSet::new();
+
+        while sleep_unless_stopped(deferral.check_interval, &stop) {
+            let pending = match persistence.deferred_actions(&sftp_source.name) {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Could not read deferred actions of source '{}': {}", &sftp_source.name, e);
+                    continue;
+                }
+            };
+
+            let mut waiting: i64 = 0;
+            let mut stale: i64 = 0;
+
+            for deferred in pending {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let missing = missing_targets(&connections, &source_directory, &deferred);
+
+                if missing.is_empty() && perform(&sftp_source, &mut sftp_connection, &deferred, &persistence) {
+                    reported.remove(&deferred.sftp_download_id);
+                    continue;
+                }
+
+                waiting += 1;
+
+                if deferred.age >= deferral.stale_after as i64 {
+                    stale += 1;
+
+                    if reported.insert(deferred.sftp_download_id) {
+                        warn!(
+                            "[E01019] Post-download action on <{}> '{}' pending for {} s, not dispatched to: {}",
+                            &sftp_source.name, &deferred.remote_path, deferred.age / 1000, missing.join(", ")
+                        );
+                    }
+                }
+            }
+
+            metrics::DEFERRED_ACTIONS_GAUGE
+                .with_label_values(&[&sftp_source.name, "false"])
+                .set(waiting - stale);
+            metrics::DEFERRED_ACTIONS_GAUGE
+                .with_label_values(&[&sftp_source.name, "true"])
+                .set(stale);
+        }
+
+        debug!("Deferred actions of source '{}' stopped", &sftp_source.name);
+    })
+}
+
+/// Targets of connections matching the file that it was not dispatched to yet
+fn missing_targets(connections: &[settings::Connection], source_directory: &Path, deferred: &DeferredAction) -> Vec<String> {
+    connections.iter()
+        .filter(|c| match &c.filter {
+            Some(f) => f.stored_file_matches(source_directory, &deferred.local_path, deferred.size as u64, deferred.modified),
+            None => true
+        })
+        .filter(|c| !deferred.dispatched.contains(&c.target))
+        .map(|c| c.target.clone())
+        .collect()
+}
+
+/// Perform a deferred action and record the outcome. Returns false when the
+/// action failed and has to be tried again.
+fn perform<T: Persistence>(
+    sftp_source: &settings::SftpSource,
+    sftp_connection: &mut Option<SftpConnection>,
+    deferred: &DeferredAction,
+    persistence: &T,
+) -> bool {
+    let action: PostDownloadAction = match serde_json::from_str(&deferred.action) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("[E01018] Invalid deferred action '{}': {}", &deferred.action, e);
+            return false
+        }
+    };
+
+    let connection = match sftp_connection.take() {
+        Some(c) => c,
+        None => match SftpConnection::connect(sftp_config(sftp_source)) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("Could not connect to {} for deferred actions: {}", &sftp_source.address, e);
+                return false
+            }
+        }
+    };
+
+    match post_download::execute(&connection.sftp, &action, Path::new(&deferred.remote_path)) {
+        Ok(outcome) => {
+            debug!("Deferred action on <{}> '{}': {}", &sftp_source.name, &deferred.remote_path, outcome.as_deref().unwrap_or("none"));
+
+            *sftp_connection = Some(connection);
+
+            if let Err(e) = persistence.set_sftp_download_post_action(deferred.sftp_download_id, outcome.as_deref(), None) {
+                warn!("Could not record post-download action of <{}> '{}': {}", &sftp_source.name, &deferred.remote_path, e);
+            }
+
+            if let Err(e) = persistence.remove_deferred_action(deferred.sftp_download_id) {
+                error!("Could not remove deferred action of <{}> '{}': {}", &sftp_source.name, &deferred.remote_path, e);
+            }
+
+            true
+        },
+        Err(e) => {
+            let msg_list: Vec<String> = e.iter().map(|sub_err| sub_err.to_string()).collect();
+            let error = msg_list.join(": ");
+
+            // The connection is dropped, because the failure may be caused by
+            // a broken connection.
+            error!(
+                "[E01018] Deferred action on <{}> '{}' failed: {}",
+                &sftp_source.name, &deferred.remote_path, &error
+            );
+
+            metrics::POST_DOWNLOAD_FAILURES_COUNTER
+                .with_label_values(&[&sftp_source.name])
+                .inc();
+
+            if let Err(e) = persistence.set_sftp_download_post_action(deferred.sftp_download_id, None, Some(&error)) {
+                warn!("Could not record post-download action of <{}> '{}': {}", &sftp_source.name, &deferred.remote_path, e);
+            }
+
+            false
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use postgres::tls::{MakeTlsConnect, TlsConnect};
+use tokio_postgres::Socket;
+
+use crate::base_types::Target;
+use crate::event::FileEvent;
+use crate::metrics;
+use crate::persistence::PostgresAsyncPersistence;
+use crate::settings::DeliveryRetry;
+
+/// Maximum number of due deliveries that are picked up in one round
+const REDELIVERY_BATCH_SIZE: i64 = 100;
+
+/// Record a failed delivery of a file to a target, so that it is retried later.
+pub async fn record_failure<T>(
+    persistence: &PostgresAsyncPersistence<T>,
+    policy: &DeliveryRetry,
+    target_name: &str,
+    file_id: i64,
+    error: &str,
+)
+where
+    T: MakeTlsConnect<Socket> + Clone + 'static + Sync + Send,
+    T::TlsConnect: Send,
+    T::Stream: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    metrics::DELIVERY_FAILURES_COUNTER
+        .with_label_values(&[target_name])
+        .inc();
+
+    match persistence.record_delivery_failure(target_name, file_id, error, policy).await {
+        Ok((attempts, state)) => {
+            if state == "dead" {
+                error!("[E01013] Delivery of file {} to target '{}' failed {} times, giving up: {}", file_id, target_name, attempts, error);
+            } else {
+                warn!("Delivery of file {} to target '{}' failed (attempt {}), will retry: {}", file_id, target_name, attempts, error);
+            }
+        },
+        Err(e) => {
+            error!("[E01014] Could not record failed delivery of file {} to target '{}': {}", file_id, target_name, e);
+        }
+    }
+}
+
+/// Remove the retry record of a file for a target after a successful delivery.
+pub async fn record_success<T>(
+    persistence: &PostgresAsyncPersistence<T>,
+    target_name: &str,
+    file_id: i64,
+)
+where
+    T: MakeTlsConnect<Socket> + Clone + 'static + Sync + Send,
+    T::TlsConnect: Send,
+    T::Stream: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    if let Err(e) = persistence.clear_delivery_attempt(target_name, file_id).await {
+        error!("Could not clear delivery attempt of file {} to target '{}': {}", file_id, target_name, e);
+    }
+}
+
+/// Periodically re-send file events for deliveries that are due for a retry
+/// and update the delivery attempt metrics.
+pub async fn run_redelivery<T>(
+    persistence: PostgresAsyncPersistence<T>,
+    policy: DeliveryRetry,
+    targets: Arc<Mutex<HashMap<String, Arc<Target>>>>,
+)
+where
+    T: MakeTlsConnect<Socket> + Clone + 'static + Sync + Send,
+    T::TlsConnect: Send,
+    T::Stream: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    let mut interval = tokio::time::interval(Duration::from_millis(policy.interval));
+
+    loop {
+        interval.tick().await;
+
+        // Claimed deliveries are postponed by the maximum delay, a failure
+        // reschedules them according to the backoff policy.
+        let claim_result = persistence.claim_due_deliveries(policy.max_delay, REDELIVERY_BATCH_SIZE).await;
+
+        match claim_result {
+            Ok(due_deliveries) => {
+                for due in due_deliveries {
+                    let target = targets.lock().unwrap().get(&due.target).cloned();
+
+                    let error = match target {
+                        Some(t) => {
+                            debug!("Retrying delivery of file {} to target '{}'", due.file_id, &due.target);
+
+                            let file_event = FileEvent {
+                                file_id: due.file_id,
+                                source_name: due.source.clone(),
+                                path: due.path.clone(),
+                            };
+
+                            match t.sender.send(file_event) {
+                                Ok(_) => None,
+                                Err(e) => Some(format!("Could not send event to target handler: {}", e)),
+                            }
+                        },
+                        None => Some(format!("No target '{}' configured", &due.target)),
+                    };
+
+                    if let Some(e) = error {
+                        record_failure(&persistence, &policy, &due.target, due.file_id, &e).await;
+                    }
+                }
+            },
+            Err(e) => error!("Could not read due deliveries: {}", e),
+        }
+
+        match persistence.delivery_attempt_counts().await {
+            Ok(counts) => {
+                metrics::DELIVERY_ATTEMPTS_GAUGE.reset();
+
+                for (target, state, count) in counts {
+                    metrics::DELIVERY_ATTEMPTS_GAUGE
+                        .with_label_values(&[&target, &state])
+                        .set(count);
+                }
+            },
+            Err(e) => error!("Could not read delivery attempt counts: {}", e),
+        }
+    }
+}
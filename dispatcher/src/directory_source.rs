@@ -117,7 +117,7 @@ pub fn start_directory_sweep(
 
                 let mut handle_file = |path: &Path| {
                     let file_matches = match &directory_source.filter {
-                        Some(f) => f.file_matches(path),
+                        Some(f) => f.file_matches_in(&directory_source.directory, path),
                         None => true,
                     };
 
@@ -153,7 +153,12 @@ pub fn start_directory_sweep(
                 }
             });
 
-            std::thread::sleep(timeout);
+            // Sleep in short steps to respond to the stop flag in time
+            let next_sweep = std::time::Instant::now() + timeout;
+
+            while !stop_flag.load(Ordering::Relaxed) && std::time::Instant::now() < next_sweep {
+                std::thread::sleep(Duration::from_millis(500));
+            }
         }
 
         debug!("Directory sweep thread ended")
@@ -250,10 +255,15 @@ fn start_inotify_event_thread(
         let mut buffer: Vec<u8> = vec![0; 1024];
 
         while !stop_flag.load(Ordering::Relaxed) {
-            let read_result = inotify.read_events_blocking(&mut buffer);
+            // Non-blocking read, so that the stop flag is checked regularly
+            let read_result = inotify.read_events(&mut buffer);
 
             let events = match read_result {
                 Ok(events) => events,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(timeout);
+                    continue
+                },
                 Err(e) => {
                     error!("Could not read inotify events: {}", e);
                     std::thread::sleep(timeout);
@@ -314,7 +324,7 @@ fn start_inotify_event_thread(
                             }
                         } else {
                             let file_matches = match &event_context.filter {
-                                Some(f) => f.file_matches(&source_path),
+                                Some(f) => f.file_matches_in(&event_context.prefix, &source_path),
                                 None => true,
                             };
                     
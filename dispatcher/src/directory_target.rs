@@ -133,12 +133,14 @@ where
         }
     };
 
-    if placement_result.is_ok() {
-        let set_result = set_permissions(&target_path, target_perms);
+    if placement_result.is_err() {
+        return Err(format!("Could not place '{}' at '{}'", &source_path_str, &target_path_str));
+    }
 
-        if let Err(e) = set_result {
-            error!("Could not set file permissions on '{}': {}", &target_path_str, e)
-        }
+    let set_result = set_permissions(&target_path, target_perms);
+
+    if let Err(e) = set_result {
+        error!("Could not set file permissions on '{}': {}", &target_path_str, e)
     }
 
     let insert_result = persistence.insert_dispatched(&target_name, file_event.file_id).await;
@@ -2,31 +2,31 @@ use futures::future::join_all;
 use std::thread;
 use std::collections::HashMap;
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "linux")]
 extern crate inotify;
 
-use failure::{Error};
+use failure::{Error, err_msg};
 
-extern crate lapin;
-use lapin::{ConnectionProperties};
-
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio::sync::oneshot;
-use tokio_stream::StreamExt;
 
 use postgres::NoTls;
 use r2d2_postgres::PostgresConnectionManager;
 
-use signal_hook_tokio::v0_3::Signals;
-
 use crossbeam_channel::{bounded, Sender, Receiver};
 
-use cortex_core::{wait_for, SftpDownload, StopCmd};
+use cortex_core::{wait_for, HttpDownload, SftpDownload, StopCmd};
 
-use crate::base_types::{Connection, RabbitMQNotify, Target, Source};
+use crate::base_types::{compile_templates, AmqpHealth, Connection, Notifier, Target, Source};
+use crate::backfill;
+use crate::deferred_action;
+use crate::delivery;
 
 use crate::directory_source::{start_directory_sweep, start_local_intake_thread};
 #[cfg(target_os = "linux")]
@@ -34,13 +34,21 @@ use crate::directory_source::start_directory_sources;
 
 use crate::directory_target::handle_file_event;
 use crate::event::{FileEvent, EventDispatcher};
+use crate::http_downloader;
 use crate::http_server::start_http_server;
+use crate::http_target::{self, HttpUploader};
 use crate::persistence::{PostgresPersistence, PostgresAsyncPersistence};
 use crate::settings;
 use crate::sftp_downloader;
 use crate::sftp_command_consumer;
+use crate::sftp_target;
+use crate::throttle::Throttle;
 use crate::base_types::MessageResponse;
 use crate::local_storage::LocalStorage;
+use crate::metrics;
+
+/// Interval at which the SFTP download state gauges are refreshed
+const DOWNLOAD_STATE_METRICS_INTERVAL: Duration = Duration::from_secs(15);
 
 struct Stop {
     stop_commands: Vec<StopCmd>
@@ -70,7 +78,46 @@ impl Stop {
     }
 }
 
+/// Compile the notification templates of all targets, so that configuration
+/// errors are reported before anything is started.
+fn check_notify_templates(settings: &settings::Settings) -> Result<(), Error> {
+    let directory_notify = settings.directory_targets.iter().map(|t| (&t.name, &t.notify));
+    let sftp_notify = settings.sftp_targets.iter().map(|t| (&t.name, &t.notify));
+    let http_notify = settings.http_targets.iter().map(|t| (&t.name, &t.notify));
+
+    for (target_name, notify) in directory_notify.chain(sftp_notify).chain(http_notify) {
+        if let Some(notify_conf) = notify {
+            let compile_result = match notify_conf {
+                settings::Notify::RabbitMQ(n) => compile_templates(&n.message_template, &n.headers),
+                settings::Notify::Webhook(n) => compile_templates(&n.message_template, &HashMap::new()),
+            };
+
+            if let Err(e) = compile_result {
+                return Err(err_msg(format!("Invalid notification template for target '{}': {}", target_name, e)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Configure the uploaders of all HTTP targets, so that an invalid URL
+/// template or header is reported before anything is started.
+fn http_uploaders(settings: &settings::Settings) -> Result<Vec<Arc<HttpUploader>>, Error> {
+    settings.http_targets.iter()
+        .map(|target_conf| {
+            HttpUploader::new(target_conf)
+                .map(Arc::new)
+                .map_err(|e| err_msg(format!("Error configuring HTTP target '{}': {}", &target_conf.name, e)))
+        })
+        .collect()
+}
+
 pub fn run(settings: settings::Settings) -> Result<(), Error> {
+    check_notify_templates(&settings)?;
+
+    let uploaders = http_uploaders(&settings)?;
+
     let runtime = tokio::runtime::Runtime::new()?;
 
     // List of targets with their file event channels
@@ -81,18 +128,29 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
 
     let connections: Arc<Mutex<Vec<Connection>>> = Arc::new(Mutex::new(Vec::new()));
 
-    // Stop orchestrator
+    // Stop orchestrator for everything that takes in new work
     let stop: Arc<Mutex<Stop>> = Arc::new(Mutex::new(Stop::new()));
 
+    // Stop orchestrator for the targets, which are flushed last
+    let target_stop: Arc<Mutex<Stop>> = Arc::new(Mutex::new(Stop::new()));
+
+    type TargetJoinHandle = tokio::task::JoinHandle<()>;
+
+    let target_join_handles: Arc<Mutex<Vec<TargetJoinHandle>>> = Arc::new(Mutex::new(Vec::new()));
+
     let connection_manager =
         PostgresConnectionManager::new(settings.postgresql.url.parse().unwrap(), NoTls);
 
     let tokio_connection_manager = 
         bb8_postgres::PostgresConnectionManager::new(settings.postgresql.url.parse().unwrap(), tokio_postgres::NoTls);
 
+    let async_connection_manager = tokio_connection_manager.clone();
+
     let t_settings = settings.clone();
 
-    let directory_target_stop = stop.clone();
+    let directory_target_stop = target_stop.clone();
+
+    let directory_target_join_handles = target_join_handles.clone();
 
     let directory_target_targets = targets.clone();
 
@@ -101,90 +159,55 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
 
         t_settings.directory_targets.iter().for_each(|target_conf| {
             let persistence = tokio_persistence.clone();
-            let (sender, mut receiver) = unbounded_channel();
+            let retry_policy = t_settings.delivery_retry.clone();
+            let (sender, mut receiver) = unbounded_channel::<FileEvent>();
 
             let c_target_conf = target_conf.clone();
             let d_target_conf = target_conf.clone();
         
             let (stop_sender, stop_receiver) = oneshot::channel::<()>();
+            let mut stop_receiver = Some(stop_receiver);
         
-            match c_target_conf.notify {
-                Some(conf) => match conf {
-                    settings::Notify::RabbitMQ(notify_conf) => {
-                        let fut = async move {
-                            debug!("Connecting notifier to directory target stream");
-        
-                            let connect_result = lapin::Connection::connect(
-                                &notify_conf.address,
-                                lapin::ConnectionProperties::default(),
-                            ).await;
-
-                            let connection = match connect_result {
-                                Ok(c) => c,
-                                Err(e) => {
-                                    error!("Error connecting to AMQP service: {}", e);
-                                    return
-                                }
-                            };
-                                              
-                            let amqp_channel_result = connection.create_channel().await;
-
-                            let amqp_channel = match amqp_channel_result {
-                                Ok(c) => c,
-                                Err(e) => {
-                                    error!("Error creating AMQP channel: {}", e);
-                                    return
-                                }
-                            };
-                                        
-                            let notify = RabbitMQNotify {
-                                message_template: notify_conf.message_template.clone(),
-                                //channel: amqp_channel,
-                                exchange: notify_conf.exchange.clone(),
-                                routing_key: notify_conf.routing_key.clone(),
+            let join_handle = tokio::spawn(async move {
+                let notifier = match &d_target_conf.notify {
+                    Some(notify_conf) => {
+                        debug!("Connecting notifier to directory target stream");
+
+                        match Notifier::connect(notify_conf).await {
+                            Ok(n) => Some(n),
+                            Err(e) => {
+                                error!("{}", e);
+                                return
+                            }
+                        }
+                    },
+                    None => None
+                };
+
+                while let Some(file_event) = next_event(&mut receiver, &mut stop_receiver).await {
+                    let file_id = file_event.file_id;
+
+                    match handle_file_event(&d_target_conf, file_event, persistence.clone()).await {
+                        Ok(result_event) => {
+                            let notify_result = match &notifier {
+                                Some(n) => n.notify(&d_target_conf.name, result_event, &persistence).await,
+                                None => Ok(())
                             };
 
-                            let routing_key = notify_conf.routing_key.clone();
-        
-                            while let Some(file_event) = receiver.recv().await {        
-                                match handle_file_event(&d_target_conf, file_event, persistence.clone()).await {
-                                    Ok(result_event) => {
-                                        debug!("Notifying with AMQP routing key {}", &routing_key);
-        
-                                        notify.notify(&amqp_channel, result_event).await;        
-                                    },
-                                    Err(e) => {
-                                        error!("Error handling event for directory target: {}", &e);
-                                    }
-                                }        
-                            }
-                        };
-        
-                        tokio::spawn(async move {
-                            tokio::select!(
-                                _a = fut => (),
-                                _b = stop_receiver => ()
-                            )
-                        })
-                    }
-                },
-                None => {
-                    let fut = async move {
-                        while let Some(file_event) = receiver.recv().await {        
-                            if let Err(e) = handle_file_event(&d_target_conf, file_event, persistence.clone()).await {
-                                error!("Error handling event for directory target: {}", &e);
+                            match notify_result {
+                                Ok(_) => delivery::record_success(&persistence, &d_target_conf.name, file_id).await,
+                                Err(e) => delivery::record_failure(&persistence, &retry_policy, &d_target_conf.name, file_id, &e).await,
                             }
+                        },
+                        Err(e) => {
+                            error!("Error handling event for directory target: {}", &e);
+                            delivery::record_failure(&persistence, &retry_policy, &d_target_conf.name, file_id, &e).await;
                         }
-                    };
-        
-                    tokio::spawn(async move {
-                        tokio::select!(
-                            _a = fut => (),
-                            _b = stop_receiver => ()
-                        )
-                    })
-                },
-            };
+                    }
+                }
+            });
+
+            directory_target_join_handles.lock().unwrap().push(join_handle);
         
             let stop_cmd_name = c_target_conf.name.clone();
         
@@ -206,6 +229,143 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
         
             directory_target_targets.lock().unwrap().insert(target_conf.name.clone(), target);
         });
+
+        t_settings.sftp_targets.iter().for_each(|target_conf| {
+            let persistence = tokio_persistence.clone();
+            let retry_policy = t_settings.delivery_retry.clone();
+            let (sender, receiver) = unbounded_channel::<FileEvent>();
+            let (stop_sender, stop_receiver) = oneshot::channel::<()>();
+
+            let events: SharedEvents = Arc::new(tokio::sync::Mutex::new((receiver, Some(stop_receiver))));
+
+            let c_target_conf = target_conf.clone();
+
+            let join_handle = tokio::spawn(async move {
+                let notifier = match &c_target_conf.notify {
+                    Some(notify_conf) => {
+                        debug!("Connecting notifier to SFTP target stream");
+
+                        match Notifier::connect(notify_conf).await {
+                            Ok(n) => Some(Arc::new(n)),
+                            Err(e) => {
+                                error!("{}", e);
+                                return
+                            }
+                        }
+                    },
+                    None => None
+                };
+
+                let throttle = Arc::new(Throttle::new(
+                    c_target_conf.max_bytes_per_second,
+                    c_target_conf.bandwidth_schedule.clone(),
+                    metrics::SFTP_UPLOAD_RATE_GAUGE.with_label_values(&[&c_target_conf.name]),
+                ));
+
+                // Each worker uploads with its own SFTP connection
+                let workers: Vec<tokio::task::JoinHandle<()>> = (0..c_target_conf.thread_count).map(|_| {
+                    tokio::spawn(sftp_target_worker(
+                        c_target_conf.clone(), events.clone(), notifier.clone(), persistence.clone(), retry_policy.clone(), throttle.clone()
+                    ))
+                }).collect();
+
+                // Keep the throughput gauge current while no uploads are running
+                let gauge_throttle = throttle.clone();
+
+                let gauge_ticker = tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+                    loop {
+                        interval.tick().await;
+                        gauge_throttle.tick();
+                    }
+                });
+
+                join_all(workers).await;
+
+                gauge_ticker.abort();
+            });
+
+            directory_target_join_handles.lock().unwrap().push(join_handle);
+
+            let stop_cmd_name = target_conf.name.clone();
+
+            let stop_cmd = Box::new(move || {
+                let send_result = stop_sender.send(());
+
+                match send_result {
+                    Ok(_) => debug!("Stop command sent for SFTP target '{}'", &stop_cmd_name),
+                    Err(e) => debug!("Error sending stop command for SFTP target '{}': {:?}", &stop_cmd_name, e)
+                }
+            });
+
+            let target = Arc::new(Target {
+                name: target_conf.name.clone(),
+                sender,
+            });
+
+            directory_target_stop.lock().unwrap().add_command(stop_cmd);
+
+            directory_target_targets.lock().unwrap().insert(target_conf.name.clone(), target);
+        });
+
+        t_settings.http_targets.iter().zip(uploaders).for_each(|(target_conf, uploader)| {
+            let persistence = tokio_persistence.clone();
+            let retry_policy = t_settings.delivery_retry.clone();
+            let (sender, receiver) = unbounded_channel::<FileEvent>();
+            let (stop_sender, stop_receiver) = oneshot::channel::<()>();
+
+            let events: SharedEvents = Arc::new(tokio::sync::Mutex::new((receiver, Some(stop_receiver))));
+
+            let c_target_conf = target_conf.clone();
+
+            let join_handle = tokio::spawn(async move {
+                let notifier = match &c_target_conf.notify {
+                    Some(notify_conf) => {
+                        debug!("Connecting notifier to HTTP target stream");
+
+                        match Notifier::connect(notify_conf).await {
+                            Ok(n) => Some(Arc::new(n)),
+                            Err(e) => {
+                                error!("{}", e);
+                                return
+                            }
+                        }
+                    },
+                    None => None
+                };
+
+                let workers: Vec<tokio::task::JoinHandle<()>> = (0..c_target_conf.thread_count).map(|_| {
+                    tokio::spawn(http_target_worker(
+                        uploader.clone(), events.clone(), notifier.clone(), persistence.clone(), retry_policy.clone()
+                    ))
+                }).collect();
+
+                join_all(workers).await;
+            });
+
+            directory_target_join_handles.lock().unwrap().push(join_handle);
+
+            let stop_cmd_name = target_conf.name.clone();
+
+            let stop_cmd = Box::new(move || {
+                let send_result = stop_sender.send(());
+
+                match send_result {
+                    Ok(_) => debug!("Stop command sent for HTTP target '{}'", &stop_cmd_name),
+                    Err(e) => debug!("Error sending stop command for HTTP target '{}': {:?}", &stop_cmd_name, e)
+                }
+            });
+
+            let target = Arc::new(Target {
+                name: target_conf.name.clone(),
+                sender,
+            });
+
+            directory_target_stop.lock().unwrap().add_command(stop_cmd);
+
+            directory_target_targets.lock().unwrap().insert(target_conf.name.clone(), target);
+        });
     });
 
     let persistence = PostgresPersistence::new(connection_manager);
@@ -267,12 +427,27 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
             );
         });
 
+    // Stop flag for the downloader threads, which is set after the command
+    // consumers have stopped.
     let stop_flag = Arc::new(AtomicBool::new(false));
-    let stop_clone = stop_flag.clone();
-
-    stop.lock().unwrap().add_command(Box::new(move || {
-        stop_clone.swap(true, Ordering::Relaxed);
-    }));
+    let downloader_stop_flag = stop_flag.clone();
+
+    // Deferred post-download actions are performed outside the downloaders,
+    // once the files have been dispatched.
+    let deferred_join_handles: Vec<thread::JoinHandle<()>> = settings.sftp_sources.iter()
+        .filter_map(|sftp_source| {
+            sftp_source.deferred_actions.clone().map(|deferral| {
+                deferred_action::start(
+                    stop_flag.clone(),
+                    sftp_source.clone(),
+                    deferral,
+                    settings.connections.clone(),
+                    settings.storage.directory.clone(),
+                    persistence.clone(),
+                )
+            })
+        })
+        .collect();
 
     type SftpJoinHandle = thread::JoinHandle<std::result::Result<(), sftp_downloader::Error>>;
 
@@ -287,7 +462,9 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
     }
 
     let (sftp_source_senders, mut sftp_sources): (Vec<SftpSourceSend>, Vec<Source>) = settings.sftp_sources.iter().map(|sftp_source| {
-        let (cmd_sender, cmd_receiver) = bounded::<(u64, SftpDownload)>(10);
+        // Every delivered command is unacknowledged until its download is done,
+        // so with room for the prefetch count the channel never fills up.
+        let (cmd_sender, cmd_receiver) = bounded::<(u64, SftpDownload)>(sftp_source.prefetch_count() as usize);
         let (file_event_sender, file_event_receiver) = unbounded_channel();
         let (stop_sender, stop_receiver) = oneshot::channel::<()>();
 
@@ -316,25 +493,68 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
 
     sources.append(&mut sftp_sources);
 
+    type HttpJoinHandle = thread::JoinHandle<std::result::Result<(), http_downloader::Error>>;
+
+    let http_join_handles: Arc<Mutex<Vec<HttpJoinHandle>>> = Arc::new(Mutex::new(Vec::new()));
+
+    struct HttpSourceSend {
+        pub http_source: settings::HttpSource,
+        pub cmd_sender: Sender<(u64, HttpDownload)>,
+        pub cmd_receiver: Receiver<(u64, HttpDownload)>,
+        pub file_event_sender: tokio::sync::mpsc::UnboundedSender<FileEvent>,
+        pub stop_receiver: oneshot::Receiver<()>
+    }
+
+    let (http_source_senders, mut http_sources): (Vec<HttpSourceSend>, Vec<Source>) = settings.http_sources.iter().map(|http_source| {
+        let (cmd_sender, cmd_receiver) = bounded::<(u64, HttpDownload)>(http_source.prefetch_count() as usize);
+        let (file_event_sender, file_event_receiver) = unbounded_channel();
+        let (stop_sender, stop_receiver) = oneshot::channel::<()>();
+
+        stop.lock().unwrap().add_command(Box::new(move || {
+            match stop_sender.send(()) {
+                Ok(_) => (),
+                Err(e) => error!("[E02008] Error sending stop signal: {:?}", e)
+            }
+        }));
+
+        let http_source_send = HttpSourceSend {
+            http_source: http_source.clone(),
+            cmd_sender,
+            cmd_receiver,
+            file_event_sender,
+            stop_receiver,
+        };
+
+        let source = Source {
+            name: http_source.name.clone(),
+            receiver: file_event_receiver
+        };
+
+        (http_source_send, source)
+    }).unzip();
+
+    sources.append(&mut http_sources);
+
     let jhs = sftp_join_handles.clone();
+    let http_jhs = http_join_handles.clone();
 
     let l_settings = settings.clone();
 
-    let _sftp_sources_join_handle = runtime.spawn(async move {
-        debug!("Connecting to AMQP service at {}", &l_settings.command_queue.address);
-        
-        let amqp_client = lapin::Connection::connect(
-            &l_settings.command_queue.address,
-            ConnectionProperties::default(),
-        ).await?;
-
-        debug!("Connected to AMQP service");
+    let amqp_health = AmqpHealth::default();
+    let consumer_health = amqp_health.clone();
 
-        let mut stream_join_handles: Vec<tokio::task::JoinHandle<Result<(), sftp_command_consumer::ConsumeError>>> = Vec::new();
+    let sources_join_handle = runtime.spawn(async move {
+        let mut consumer_join_handles: Vec<tokio::task::JoinHandle<Result<(), sftp_command_consumer::ConsumeError>>> = Vec::new();
         
         for channels in sftp_source_senders {
             let (ack_sender, ack_receiver) = tokio::sync::mpsc::channel::<MessageResponse>(100);
 
+            let throttle = Arc::new(Throttle::new(
+                channels.sftp_source.max_bytes_per_second,
+                channels.sftp_source.bandwidth_schedule.clone(),
+                metrics::SFTP_DOWNLOAD_RATE_GAUGE.with_label_values(&[&channels.sftp_source.name]),
+            ));
+
             for n in 0..channels.sftp_source.thread_count {
                 debug!("Starting SFTP download thread '{}'", &channels.sftp_source.name);
 
@@ -346,6 +566,8 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
                     channels.file_event_sender.clone(),
                     local_storage.clone(),
                     persistence.clone(),
+                    throttle.clone(),
+                    l_settings.connections.clone(),
                 );
 
                 let guard = jhs.lock();
@@ -355,27 +577,63 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
                 info!("Started SFTP download thread '{}' ({})", &channels.sftp_source.name, n + 1);
             }
 
-            let amqp_channel = amqp_client.create_channel().await?;
+            debug!("Spawning AMQP consumer task '{}'", &channels.sftp_source.name);
 
-            debug!("Spawning AMQP stream task '{}'", &channels.sftp_source.name);
+            consumer_join_handles.push(tokio::spawn(sftp_command_consumer::supervise(
+                l_settings.command_queue.clone(),
+                sftp_command_consumer::SourceQueue {
+                    source_name: channels.sftp_source.name.clone(),
+                    prefetch: channels.sftp_source.prefetch_count(),
+                    dead_letter: channels.sftp_source.dead_letter.clone(),
+                },
+                channels.cmd_sender.clone(),
+                channels.cmd_receiver.clone(),
+                ack_receiver,
+                channels.stop_receiver,
+                consumer_health.clone(),
+            )));
+        }
 
-            let consume_future = sftp_command_consumer::start(
-                amqp_channel,
-                channels.sftp_source.name.clone(),
-                ack_receiver, channels.cmd_sender.clone()
-            );
+        for channels in http_source_senders {
+            let (ack_sender, ack_receiver) = tokio::sync::mpsc::channel::<MessageResponse>(100);
 
-            stream_join_handles.push(tokio::spawn(async {
-                tokio::select!(
-                    a = consume_future => a,
-                    _b = channels.stop_receiver => {
-                        debug!("Interrupted SFTP command consumer stream '{}'", &channels.sftp_source.name);
-                        Ok(())
-                    }
-                )    
-            }));
+            for n in 0..channels.http_source.thread_count {
+                debug!("Starting HTTP download thread '{}'", &channels.http_source.name);
+
+                let join_handle = http_downloader::HttpDownloader::start(
+                    stop_flag.clone(),
+                    channels.cmd_receiver.clone(),
+                    ack_sender.clone(),
+                    channels.http_source.clone(),
+                    channels.file_event_sender.clone(),
+                    local_storage.clone(),
+                    persistence.clone(),
+                );
+
+                http_jhs.lock().unwrap().push(join_handle);
+
+                info!("Started HTTP download thread '{}' ({})", &channels.http_source.name, n + 1);
+            }
+
+            debug!("Spawning AMQP consumer task '{}'", &channels.http_source.name);
+
+            consumer_join_handles.push(tokio::spawn(sftp_command_consumer::supervise(
+                l_settings.command_queue.clone(),
+                sftp_command_consumer::SourceQueue {
+                    source_name: channels.http_source.name.clone(),
+                    prefetch: channels.http_source.prefetch_count(),
+                    dead_letter: channels.http_source.dead_letter.clone(),
+                },
+                channels.cmd_sender.clone(),
+                channels.cmd_receiver.clone(),
+                ack_receiver,
+                channels.stop_receiver,
+                consumer_health.clone(),
+            )));
         }
 
+        let async_persistence = PostgresAsyncPersistence::new(async_connection_manager).await;
+
         let dispatcher_join_handles: Vec<tokio::task::JoinHandle<Result<(), ()>>> = sources.into_iter().map(|source| -> tokio::task::JoinHandle<Result<(), ()>> {
             // Filter connections to this source
             let source_connections: Vec<Connection> = connections.lock().unwrap()
@@ -386,47 +644,121 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
 
             debug!("Spawing local event dispatcher task for {}", &source.name);
 
-            tokio::spawn(dispatch_stream(source, source_connections))
+            tokio::spawn(dispatch_stream(source, source_connections, l_settings.storage.directory.clone(), async_persistence.clone(), l_settings.delivery_retry.clone()))
         }).collect();
 
-        // Await on futures so that the AMQP connection does not get destroyed.
-        let stream_results = join_all(stream_join_handles).await;
+        let backfill_connections = connections.lock().unwrap().clone();
+
+        tokio::spawn(backfill::run_backfill(async_persistence.clone(), backfill_connections, l_settings.storage.directory.clone(), l_settings.backfill.clone()));
+
+        if !l_settings.sftp_sources.is_empty() {
+            tokio::spawn(update_download_state_metrics(async_persistence.clone()));
+        }
+
+        tokio::spawn(delivery::run_redelivery(async_persistence, l_settings.delivery_retry.clone(), targets));
+
+        // The consumers end when the downloader threads have stopped, after
+        // which the last acks and requeues have been sent.
+        for join_result in join_all(consumer_join_handles).await {
+            match join_result {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => error!("Error in command consumer: {}", e),
+                Err(e) => error!("Error joining command consumer task: {}", e),
+            }
+        }
+
+        // The dispatch streams end when all senders of their sources are
+        // dropped, after forwarding the remaining events to the targets.
+        join_all(dispatcher_join_handles).await;
 
         Ok::<(), sftp_command_consumer::ConsumeError>(())
     });
 
     let (web_server_join_handle, actix_system, actix_http_server) = start_http_server(
         settings.http_server.address,
+        amqp_health,
     );
 
-    stop.lock().unwrap().add_command(Box::new(move || {
-        tokio::spawn(actix_http_server.stop(true));
-    }));
+    let signal_handler_join_handle = runtime.spawn(async move {
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut sigquit = signal(SignalKind::quit())?;
+
+        tokio::select!(
+            _ = sigterm.recv() => info!("Received SIGTERM"),
+            _ = sigint.recv() => info!("Received SIGINT"),
+            _ = sighup.recv() => info!("Received SIGHUP"),
+            _ = sigquit.recv() => info!("Received SIGQUIT"),
+        );
 
-    stop.lock().unwrap().add_command(Box::new(move || {
-        actix_system.stop();
-    }));
+        Ok::<(), std::io::Error>(())
+    });
 
-    let signal_handler_join_handle = runtime.spawn(async move {
-        // let mut signals = Signals::new(&[
-        //     signal_hook::consts::SIGHUP,
-        //     signal_hook::consts::SIGTERM,
-        //     signal_hook::consts::SIGINT,
-        //     signal_hook::consts::SIGQUIT,
-        // ]).unwrap();
-    
-        // let l_stop = Arc::try_unwrap(stop).unwrap().into_inner().unwrap();
-
-        // while let Some(signal) = signals.next().await {
-        //     info!("signal: {}", signal);
-        //     l_stop.stop();
-        //     break;
-        // }        
+    // Wait for a signal to shut down
+    runtime.block_on(signal_handler_join_handle)??;
+
+    let deadline = Instant::now() + Duration::from_millis(settings.drain_timeout);
+
+    info!("Shutting down, draining in-flight work for at most {} ms", settings.drain_timeout);
+
+    // Stop taking in new work: command consumers, directory sources and intake
+    {
+        let _guard = runtime.enter();
+        std::mem::replace(&mut *stop.lock().unwrap(), Stop::new()).stop();
+    }
+
+    // Let the downloaders finish the file they are working on
+    downloader_stop_flag.store(true, Ordering::Relaxed);
+
+    let mut drained = true;
+
+    // The handles are taken rather than unwrapped, because the setup task may
+    // still hold a reference when the shutdown starts early.
+    std::mem::take(&mut *sftp_join_handles.lock().unwrap()).into_iter().for_each(|jh| {
+        drained &= wait_until(jh, "sftp download", deadline);
+    });
+
+    std::mem::take(&mut *http_join_handles.lock().unwrap()).into_iter().for_each(|jh| {
+        drained &= wait_until(jh, "http download", deadline);
     });
 
-    // Wait until all tasks have finished
-    let _result = runtime
-        .block_on(signal_handler_join_handle);
+    for jh in deferred_join_handles {
+        drained &= wait_until(jh, "deferred action", deadline);
+    }
+
+    drained &= wait_until(local_intake_handle, "local intake", deadline);
+
+    // Wait for the last acks to be sent and the events to be dispatched
+    match runtime.block_on(tokio::time::timeout_at(deadline.into(), sources_join_handle)) {
+        Ok(Ok(Ok(()))) => debug!("Sources stopped"),
+        Ok(Ok(Err(e))) => error!("Error in sources: {}", e),
+        Ok(Err(e)) => error!("Error joining sources task: {}", e),
+        Err(_) => {
+            warn!("Sources did not stop in time");
+            drained = false;
+        }
+    }
+
+    // Flush the events that are queued for the targets
+    std::mem::replace(&mut *target_stop.lock().unwrap(), Stop::new()).stop();
+
+    let target_join_handles = std::mem::take(&mut *target_join_handles.lock().unwrap());
+
+    if runtime.block_on(tokio::time::timeout_at(deadline.into(), join_all(target_join_handles))).is_err() {
+        warn!("Targets did not finish in time");
+        drained = false;
+    }
+
+    {
+        let _guard = runtime.enter();
+        tokio::spawn(actix_http_server.stop(true));
+    }
+
+    actix_system.stop();
+
+    // Remaining tasks like the redelivery loop are cancelled
+    runtime.shutdown_timeout(Duration::from_millis(1000));
 
     info!("Tokio runtime shutdown");
 
@@ -435,18 +767,161 @@ pub fn run(settings: settings::Settings) -> Result<(), Error> {
 
     wait_for(web_server_join_handle, "http server");
 
-    wait_for(local_intake_handle, "local intake");
-
     wait_for(directory_sweep_join_handle, "directory sweep");
 
-    Arc::try_unwrap(sftp_join_handles).expect("still users of handles").into_inner().unwrap().into_iter().for_each(|jh| {
-        wait_for(jh, "sftp download");
-    });
+    if drained {
+        Ok(())
+    } else {
+        Err(err_msg("Shutdown drain did not complete within the drain timeout"))
+    }
+}
 
-    Ok(())
+/// Wait for a thread to finish until the deadline. Returns false if the thread
+/// is still running at the deadline, in which case it is left behind.
+fn wait_until<T>(join_handle: thread::JoinHandle<T>, thread_name: &str, deadline: Instant) -> bool {
+    while !join_handle.is_finished() {
+        if Instant::now() >= deadline {
+            warn!("{} thread did not stop in time", thread_name);
+            return false;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    wait_for(join_handle, thread_name);
+
+    true
+}
+
+/// Receive the next file event for a target. When the stop signal arrives, the
+/// channel is closed and the events that are already queued are still
+/// returned, after which None is returned.
+async fn next_event(receiver: &mut UnboundedReceiver<FileEvent>, stop_receiver: &mut Option<oneshot::Receiver<()>>) -> Option<FileEvent> {
+    if let Some(stop) = stop_receiver.as_mut() {
+        tokio::select!(
+            file_event = receiver.recv() => return file_event,
+            _ = stop => receiver.close()
+        );
+
+        *stop_receiver = None;
+    }
+
+    receiver.recv().await
+}
+
+/// File events and stop signal of a target, shared by the workers of the target
+type SharedEvents = Arc<tokio::sync::Mutex<(UnboundedReceiver<FileEvent>, Option<oneshot::Receiver<()>>)>>;
+
+/// Take file events for an SFTP target and upload them, until the target is
+/// stopped and the queued events are flushed.
+async fn sftp_target_worker(
+    target_conf: settings::SftpTarget,
+    events: SharedEvents,
+    notifier: Option<Arc<Notifier>>,
+    persistence: PostgresAsyncPersistence<tokio_postgres::NoTls>,
+    retry_policy: settings::DeliveryRetry,
+    throttle: Arc<Throttle>,
+) {
+    let mut sftp_connection = None;
+
+    loop {
+        let file_event = {
+            let mut guard = events.lock().await;
+            let (receiver, stop_receiver) = &mut *guard;
+
+            next_event(receiver, stop_receiver).await
+        };
+
+        let file_event = match file_event {
+            Some(e) => e,
+            None => break
+        };
+
+        let file_id = file_event.file_id;
+
+        let (connection, result) = sftp_target::handle_file_event(&target_conf, sftp_connection, file_event, throttle.clone(), persistence.clone()).await;
+
+        sftp_connection = connection;
+
+        match result {
+            Ok(result_event) => {
+                let notify_result = match &notifier {
+                    Some(n) => n.notify(&target_conf.name, result_event, &persistence).await,
+                    None => Ok(())
+                };
+
+                match notify_result {
+                    Ok(_) => delivery::record_success(&persistence, &target_conf.name, file_id).await,
+                    Err(e) => delivery::record_failure(&persistence, &retry_policy, &target_conf.name, file_id, &e).await,
+                }
+            },
+            Err(e) => {
+                error!("Error handling event for SFTP target: {}", &e);
+                delivery::record_failure(&persistence, &retry_policy, &target_conf.name, file_id, &e).await;
+            }
+        }
+    }
 }
 
-async fn dispatch_stream(mut source: Source, connections: Vec<Connection>) -> Result<(), ()> {
+/// Take file events for an HTTP target and upload them, until the target is
+/// stopped and the queued events are flushed.
+async fn http_target_worker(
+    uploader: Arc<HttpUploader>,
+    events: SharedEvents,
+    notifier: Option<Arc<Notifier>>,
+    persistence: PostgresAsyncPersistence<tokio_postgres::NoTls>,
+    retry_policy: settings::DeliveryRetry
+) {
+    let target_name = uploader.http_target.name.clone();
+
+    loop {
+        let file_event = {
+            let mut guard = events.lock().await;
+            let (receiver, stop_receiver) = &mut *guard;
+
+            next_event(receiver, stop_receiver).await
+        };
+
+        let file_event = match file_event {
+            Some(e) => e,
+            None => break
+        };
+
+        let file_id = file_event.file_id;
+
+        match http_target::handle_file_event(uploader.clone(), file_event, persistence.clone()).await {
+            Ok(result_event) => {
+                let notify_result = match &notifier {
+                    Some(n) => n.notify(&target_name, result_event, &persistence).await,
+                    None => Ok(())
+                };
+
+                match notify_result {
+                    Ok(_) => delivery::record_success(&persistence, &target_name, file_id).await,
+                    Err(e) => delivery::record_failure(&persistence, &retry_policy, &target_name, file_id, &e).await,
+                }
+            },
+            Err(e) => {
+                error!("Error handling event for HTTP target: {}", &e);
+                delivery::record_failure(&persistence, &retry_policy, &target_name, file_id, &e).await;
+            }
+        }
+    }
+}
+
+async fn dispatch_stream(
+    mut source: Source,
+    connections: Vec<Connection>,
+    storage_directory: PathBuf,
+    persistence: PostgresAsyncPersistence<tokio_postgres::NoTls>,
+    retry_policy: settings::DeliveryRetry
+) -> Result<(), ()> {
+    let source_directory = storage_directory.join(&source.name);
+
+    // The recorded metadata is only read when a filter needs it
+    let needs_metadata = connections.iter()
+        .any(|c| c.filter.as_ref().is_some_and(|f| f.needs_metadata()));
+
     while let Some(file_event) = source.receiver.recv().await {
         debug!(
             "FileEvent for {} connections, from {}: {}",
@@ -455,33 +930,68 @@ async fn dispatch_stream(mut source: Source, connections: Vec<Connection>) -> Re
             file_event.path.to_string_lossy()
         );
 
-        connections
+        let metadata = if needs_metadata {
+            match persistence.file_details(file_event.file_id).await {
+                Ok(details) => details.map(|d| (d.size as u64, d.modified)),
+                Err(e) => {
+                    warn!("Could not read details of file {}: {}", file_event.file_id, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let matching_connections = connections
             .deref()
             .iter()
             .filter(|c| {
-                match &c.filter {
-                    Some(f) => f.file_matches(&file_event.path),
-                    None => true
+                match (&c.filter, metadata) {
+                    (Some(f), Some((size, modified))) => f.stored_file_matches(&source_directory, &file_event.path, size, modified),
+                    (Some(f), None) => f.file_matches_in(&source_directory, &file_event.path),
+                    (None, _) => true
                 }
-            })
-            .for_each(|c| {
-                info!("Sending FileEvent to target {}", &c.target.name);
+            });
 
-                //let s = c.target.sender.clone();
-                let send_result = c.target.sender.send(file_event.clone());
+        for c in matching_connections {
+            info!("Sending FileEvent to target {}", &c.target.name);
 
-                match send_result {
-                    Ok(_) => (),
-                    Err(e) => {
-                        // Could not send file event to target
-                        // TODO: Implement retry mechanism
-                        error!("Could not send event to target handler: {}", e);
-                    }
-                }
-            });    
+            let send_result = c.target.sender.send(file_event.clone());
+
+            if let Err(e) = send_result {
+                // Could not send file event to target, so register it for a retry
+                let msg = format!("Could not send event to target handler: {}", e);
+                error!("{}", &msg);
+
+                delivery::record_failure(&persistence, &retry_policy, &c.target.name, file_event.file_id, &msg).await;
+            }
+        }
     }
 
     debug!("End of dispatch stream '{}'", &source.name);
 
     Ok(())
 }
+
+/// Periodically update the SFTP download gauges from the states recorded in
+/// the database.
+async fn update_download_state_metrics(persistence: PostgresAsyncPersistence<tokio_postgres::NoTls>) {
+    let mut interval = tokio::time::interval(DOWNLOAD_STATE_METRICS_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match persistence.sftp_download_state_counts().await {
+            Ok(counts) => {
+                metrics::SFTP_DOWNLOADS_GAUGE.reset();
+
+                for (source, state, count) in counts {
+                    metrics::SFTP_DOWNLOADS_GAUGE
+                        .with_label_values(&[&source, &state])
+                        .set(count);
+                }
+            },
+            Err(e) => error!("Could not read SFTP download state counts: {}", e),
+        }
+    }
+}
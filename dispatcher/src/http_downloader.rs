@@ -0,0 +1,365 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+
+use crate::event::FileEvent;
+use crate::metrics;
+use crate::persistence::Persistence;
+use crate::settings;
+use crate::base_types::MessageResponse;
+use crate::local_storage::LocalStorage;
+use crate::sftp_downloader::{remove_partial, temp_path};
+
+use cortex_core::HttpDownload;
+
+use sha2::{Digest, Sha256};
+use tee::TeeReader;
+
+use chrono::{Utc, DateTime};
+
+error_chain! {
+    errors {
+        PersistenceError
+        HttpStatusError(status: u16) {
+            description("unexpected HTTP status")
+            display("unexpected HTTP status {}", status)
+        }
+    }
+}
+
+pub struct HttpDownloader<T>
+where
+    T: Persistence,
+{
+    pub http_source: settings::HttpSource,
+    pub client: reqwest::blocking::Client,
+    pub persistence: T,
+    pub local_storage: LocalStorage<T>,
+}
+
+impl<T> HttpDownloader<T>
+where
+    T: Persistence,
+    T: Send,
+    T: Clone,
+    T: 'static,
+{
+    pub fn start(
+        stop: Arc<AtomicBool>,
+        receiver: Receiver<(u64, HttpDownload)>,
+        ack_sender: tokio::sync::mpsc::Sender<MessageResponse>,
+        config: settings::HttpSource,
+        sender: tokio::sync::mpsc::UnboundedSender<FileEvent>,
+        local_storage: LocalStorage<T>,
+        persistence: T,
+    ) -> thread::JoinHandle<Result<()>> {
+        thread::spawn(move || -> Result<()> {
+            proctitle::set_title("http_dl");
+
+            let client = reqwest::blocking::Client::builder()
+                .timeout(time::Duration::from_millis(config.timeout))
+                .build()
+                .chain_err(|| "Error creating HTTP client")?;
+
+            let mut http_downloader = HttpDownloader {
+                http_source: config.clone(),
+                client,
+                persistence,
+                local_storage,
+            };
+
+            let timeout = time::Duration::from_millis(500);
+
+            // Take HTTP download commands from the queue until the stop flag is set.
+            while !stop.load(Ordering::Relaxed) {
+                let receive_result = receiver.recv_timeout(timeout);
+
+                match receive_result {
+                    Ok((delivery_tag, command)) => {
+                        // A command received while stopping is left for the next run
+                        if stop.load(Ordering::Relaxed) {
+                            send_response(&ack_sender, MessageResponse::Requeue{delivery_tag});
+                            break;
+                        }
+
+                        match http_downloader.handle(&command) {
+                            Ok(file_event) => {
+                                send_response(&ack_sender, MessageResponse::Ack{delivery_tag});
+
+                                // Notify about new data from this HTTP source
+                                let send_result = sender.send(file_event);
+
+                                match send_result {
+                                    Ok(_) => {
+                                        debug!("Sent HTTP FileEvent to channel");
+                                    },
+                                    Err(e) => {
+                                        error!("Error notifying consumers of new file: {}", e);
+                                    }
+                                }
+                            },
+                            Err(e) => {
+                                let msg_list: Vec<String> = e.iter().map(|sub_err| sub_err.to_string()).collect();
+
+                                error!(
+                                    "[E01012] Error downloading '{}': {}",
+                                    &command.url, msg_list.join(": ")
+                                );
+
+                                // A download that was interrupted by a shutdown is retried later
+                                let response = if stop.load(Ordering::Relaxed) {
+                                    MessageResponse::Requeue{delivery_tag}
+                                } else {
+                                    MessageResponse::Nack{delivery_tag, error: msg_list.join(": "), attempts: None}
+                                };
+
+                                send_response(&ack_sender, response);
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        match e {
+                            RecvTimeoutError::Timeout => (),
+                            RecvTimeoutError::Disconnected => {
+                                // If the stop flag was set, the other side of the channel was dropped because of that, otherwise return an error
+                                if stop.load(Ordering::Relaxed) {
+                                    return Ok(())
+                                } else {
+                                    error!("[E02012] HTTP download command channel receiver disconnected");
+
+                                    return Err(Error::with_chain(e, "[E02012] HTTP download command channel receiver disconnected"));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Commands that were not started yet are put back on the queue
+            while let Ok((delivery_tag, command)) = receiver.try_recv() {
+                debug!("Requeueing download command {}", command);
+
+                if let Err(e) = ack_sender.try_send(MessageResponse::Requeue{delivery_tag}) {
+                    error!("Error sending message requeue to channel: {}", e);
+                }
+            }
+
+            debug!("HTTP source stream '{}' ended", config.name);
+
+            Ok(())
+        })
+    }
+
+    pub fn handle(&mut self, msg: &HttpDownload) -> Result<FileEvent> {
+        let url = reqwest::Url::parse(&msg.url)
+            .chain_err(|| format!("Invalid URL '{}'", &msg.url))?;
+
+        let url_path = url_path(&url);
+
+        let localize_result = self.local_storage.local_path(&self.http_source.name, url_path.as_path(), Path::new("/"));
+
+        let local_path = match localize_result {
+            Ok(p) => p,
+            Err(e) => {
+                return Err(Error::with_chain(e, "Could not localize path"))
+            }
+        };
+
+        match msg.size {
+            Some(size) => {
+                debug!(
+                    "Downloading <{}> '{}' -> '{}' {} bytes",
+                    self.http_source.name,
+                    msg.url,
+                    local_path.to_string_lossy(),
+                    size
+                );
+            }
+            None => {
+                debug!(
+                    "Downloading <{}> '{}' size unknown",
+                    self.http_source.name, msg.url
+                );
+            }
+        }
+
+        let mut response = self.client.get(url).send()
+            .chain_err(|| format!("Error requesting '{}'", &msg.url))?;
+
+        if !response.status().is_success() {
+            return Err(ErrorKind::HttpStatusError(response.status().as_u16()).into());
+        }
+
+        // Use the Last-Modified header when the server provides one, otherwise
+        // the time of download.
+        let modified = response.headers()
+            .get(reqwest::header::LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_else(Utc::now);
+
+        if let Some(local_path_parent) = local_path.parent() {
+            if !local_path_parent.exists() {
+                std::fs::create_dir_all(local_path_parent)
+                    .chain_err(||format!("Error creating containing directory '{}'", local_path_parent.to_string_lossy()))?;
+
+                info!("Created containing directory '{}'", local_path_parent.to_string_lossy());
+            }
+        }
+
+        let temp_path = temp_path(&local_path)
+            .map_err(|e| Error::with_chain(e, "Could not localize path"))?;
+
+        let mut sha256 = Sha256::new();
+
+        // The download is written to a temporary file that is moved into place
+        // when complete, so that a failed transfer leaves no truncated file.
+        let copy_result = File::create(&temp_path)
+            .chain_err(|| format!("Error creating local file '{}'", temp_path.to_string_lossy()))
+            .and_then(|mut local_file| {
+                let bytes_copied = {
+                    let mut tee_reader = TeeReader::new(&mut response, &mut sha256);
+
+                    io::copy(&mut tee_reader, &mut local_file)
+                        .chain_err(|| "Error copying file")?
+                };
+
+                local_file.sync_all()
+                    .chain_err(|| format!("Error syncing local file '{}'", temp_path.to_string_lossy()))?;
+
+                std::fs::rename(&temp_path, &local_path)
+                    .chain_err(|| format!("Error renaming '{}' to '{}'", temp_path.to_string_lossy(), local_path.to_string_lossy()))?;
+
+                Ok(bytes_copied)
+            });
+
+        let bytes_copied = match copy_result {
+            Ok(b) => b,
+            Err(e) => {
+                remove_partial(&temp_path);
+                return Err(e)
+            }
+        };
+
+        let hash = format!("{:x}", sha256.finalize());
+
+        info!(
+            "Downloaded <{}> '{}' {} bytes",
+            self.http_source.name, msg.url, bytes_copied
+        );
+
+        let file_size = match i64::try_from(bytes_copied) {
+            Ok(size) => size,
+            Err(e) => return Err(Error::with_chain(e, "Error converting bytes copied to i64"))
+        };
+
+        let file_id = match self.persistence.insert_file(
+            &self.http_source.name, &local_path.to_string_lossy(), &modified, file_size, Some(hash)
+        ) {
+            Ok(id) => id,
+            Err(e) => return Err(Error::with_chain(e, ErrorKind::PersistenceError))
+        };
+
+        metrics::FILE_DOWNLOAD_COUNTER_VEC
+            .with_label_values(&[&self.http_source.name])
+            .inc();
+        metrics::BYTES_DOWNLOADED_COUNTER_VEC
+            .with_label_values(&[&self.http_source.name])
+            .inc_by(bytes_copied);
+
+        Ok(FileEvent {
+            file_id,
+            source_name: self.http_source.name.clone(),
+            path: local_path,
+        })
+    }
+}
+
+/// Path of a URL within the storage area of its source: the host, with the
+/// port when specified, followed by the URL path. A query string is kept
+/// apart as a hash in the file name, so that URLs that only differ by host or
+/// query are stored in different files.
+fn url_path(url: &reqwest::Url) -> PathBuf {
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}_{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => "localhost".to_string(),
+    };
+
+    let segments: Vec<&str> = url.path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|s| !s.is_empty() && *s != "." && *s != "..")
+        .collect();
+
+    let mut path = Path::new("/").join(host);
+
+    path.extend(&segments);
+
+    if segments.is_empty() || url.path().ends_with('/') {
+        path.push("index");
+    }
+
+    if let Some(query) = url.query() {
+        let query_hash = format!("{:x}", Sha256::digest(query.as_bytes()));
+        let file_name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+
+        path.set_file_name(format!("{}.{}", file_name, &query_hash[..16]));
+    }
+
+    path
+}
+
+fn send_response(ack_sender: &tokio::sync::mpsc::Sender<MessageResponse>, response: MessageResponse) {
+    let send_result = ack_sender.try_send(response);
+
+    match send_result {
+        Ok(_) => {
+            debug!("Sent message response to channel");
+        },
+        Err(e) => {
+            error!("Error sending message response to channel: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path_of(url: &str) -> PathBuf {
+        url_path(&reqwest::Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn url_path_includes_host_and_port() {
+        assert_eq!(path_of("http://a.example.com/data/x.csv"), PathBuf::from("/a.example.com/data/x.csv"));
+        assert_eq!(path_of("http://a.example.com:8080/data/x.csv"), PathBuf::from("/a.example.com_8080/data/x.csv"));
+        assert_ne!(path_of("http://a.example.com/x.csv"), path_of("http://b.example.com/x.csv"));
+    }
+
+    #[test]
+    fn url_path_distinguishes_queries() {
+        let first = path_of("http://example.com/download?id=1");
+        let second = path_of("http://example.com/download?id=2");
+
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), Some(Path::new("/example.com")));
+        assert!(first.file_name().unwrap().to_string_lossy().starts_with("download."));
+    }
+
+    #[test]
+    fn url_path_names_directories_index() {
+        assert_eq!(path_of("http://example.com/"), PathBuf::from("/example.com/index"));
+        assert_eq!(path_of("http://example.com/data/"), PathBuf::from("/example.com/data/index"));
+        assert_eq!(path_of("http://example.com/a/../../etc/passwd"), PathBuf::from("/example.com/etc/passwd"));
+    }
+}
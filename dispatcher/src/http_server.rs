@@ -1,12 +1,17 @@
 use std::thread;
 
-use actix_web::{middleware, web, App, HttpRequest, HttpServer, Responder};
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 
 use prometheus::{Encoder, TextEncoder};
 
+use serde_json::json;
+
+use crate::base_types::AmqpHealth;
+
 
 pub fn start_http_server(
     addr: std::net::SocketAddr,
+    amqp_health: AmqpHealth,
 ) -> (thread::JoinHandle<()>, actix_rt::System, actix_web::dev::Server) {
     let (tx, rx) = std::sync::mpsc::channel();
     let (tx_http, rx_http) = std::sync::mpsc::channel();
@@ -16,9 +21,11 @@ pub fn start_http_server(
 
         let server = HttpServer::new(move || {
             App::new()
+                .data(amqp_health.clone())
                 .wrap(middleware::Logger::default())
                 .wrap(middleware::DefaultHeaders::new().header("Access-Control-Allow-Origin", "*"))
                 .service(web::resource("/api/metrics").to(metrics))
+                .service(web::resource("/api/health").to(health))
         })
         .disable_signals()
         .bind(addr)
@@ -55,3 +62,22 @@ async fn metrics(_req: HttpRequest) -> impl Responder {
 
     String::from_utf8(buffer).unwrap()
 }
+
+/// Health of the dispatcher, unhealthy (503) when the AMQP command consumer of
+/// any source is disconnected
+async fn health(amqp_health: web::Data<AmqpHealth>) -> HttpResponse {
+    let amqp_connections = amqp_health.states();
+
+    let healthy = amqp_connections.values().all(|connected| *connected);
+
+    let body = json!({
+        "status": if healthy { "ok" } else { "unavailable" },
+        "amqp_connections": amqp_connections,
+    });
+
+    if healthy {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
@@ -0,0 +1,310 @@
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time;
+
+use tokio_postgres::Socket;
+use postgres::tls::{MakeTlsConnect, TlsConnect};
+
+use tera::{Context, Tera};
+
+use crate::event::FileEvent;
+use crate::metrics;
+use crate::persistence::PostgresAsyncPersistence;
+use crate::settings;
+
+error_chain! {
+    errors {
+        HttpStatusError(status: u16) {
+            description("unexpected HTTP status")
+            display("unexpected HTTP status {}", status)
+        }
+    }
+}
+
+const URL_TEMPLATE_NAME: &str = "url";
+
+/// Uploads files to an HTTP endpoint, using a URL template that is compiled
+/// once at startup.
+pub struct HttpUploader {
+    pub http_target: settings::HttpTarget,
+    pub client: reqwest::Client,
+    tera: Tera,
+}
+
+impl HttpUploader {
+    pub fn new(http_target: &settings::HttpTarget) -> Result<HttpUploader> {
+        let mut tera = Tera::default();
+
+        tera.add_raw_template(URL_TEMPLATE_NAME, &http_target.url)
+            .chain_err(|| format!("Invalid URL template '{}'", &http_target.url))?;
+
+        let headers = reqwest::header::HeaderMap::try_from(&http_target.headers)
+            .chain_err(|| "Invalid HTTP header")?;
+
+        let client = reqwest::Client::builder()
+            .timeout(time::Duration::from_millis(http_target.timeout))
+            .default_headers(headers)
+            .build()
+            .chain_err(|| "Error creating HTTP client")?;
+
+        Ok(HttpUploader {
+            http_target: http_target.clone(),
+            client,
+            tera,
+        })
+    }
+
+    fn url(&self, file_event: &FileEvent) -> Result<String> {
+        let file_name = file_event.path.file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut context = Context::new();
+        context.insert("file_name", &file_name);
+        context.insert("file_path", &file_event.path);
+        context.insert("file_id", &file_event.file_id);
+        context.insert("source_name", &file_event.source_name);
+
+        self.tera.render(URL_TEMPLATE_NAME, &context)
+            .chain_err(|| "Error rendering URL template")
+    }
+
+    /// Upload the file of a file event and return the URL it was uploaded to
+    /// with the number of bytes sent. The file is streamed from disk.
+    pub async fn upload(&self, file_event: &FileEvent) -> Result<(String, u64)> {
+        let url = self.url(file_event)?;
+
+        let local_file = tokio::fs::File::open(&file_event.path).await
+            .chain_err(|| format!("Error opening local file '{}'", file_event.path.to_string_lossy()))?;
+
+        let size = local_file.metadata().await
+            .chain_err(|| format!("Error reading metadata of '{}'", file_event.path.to_string_lossy()))?
+            .len();
+
+        let request = match self.http_target.method {
+            settings::HttpUploadMethod::Put => {
+                self.client.put(&url)
+                    .header(reqwest::header::CONTENT_LENGTH, size)
+                    .body(reqwest::Body::from(local_file))
+            },
+            settings::HttpUploadMethod::Post => {
+                let file_name = file_event.path.file_name()
+                    .map(|f| f.to_string_lossy().to_string())
+                    .unwrap_or_default();
+
+                let part = reqwest::multipart::Part::stream_with_length(local_file, size)
+                    .file_name(file_name);
+
+                let form = reqwest::multipart::Form::new()
+                    .part(self.http_target.multipart_field.clone(), part);
+
+                self.client.post(&url).multipart(form)
+            }
+        };
+
+        let response = request.send().await
+            .chain_err(|| format!("Error uploading to '{}'", &url))?;
+
+        if !response.status().is_success() {
+            return Err(ErrorKind::HttpStatusError(response.status().as_u16()).into());
+        }
+
+        Ok((url, size))
+    }
+}
+
+/// Upload the file of a file event to an HTTP target.
+pub async fn handle_file_event<T>(
+    uploader: Arc<HttpUploader>,
+    file_event: FileEvent,
+    persistence: PostgresAsyncPersistence<T>
+) -> std::result::Result<FileEvent, String>
+where
+    T: MakeTlsConnect<Socket> + Clone + 'static + Sync + Send,
+    T::TlsConnect: Send,
+    T::Stream: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    let target_name = uploader.http_target.name.clone();
+
+    debug!("FileEvent for {}: '{}'", &target_name, file_event.path.to_string_lossy());
+
+    let upload_result = uploader.upload(&file_event).await;
+
+    let (url, bytes_sent) = match upload_result {
+        Ok(r) => r,
+        Err(e) => {
+            let msg_list: Vec<String> = e.iter().map(|sub_err| sub_err.to_string()).collect();
+
+            error!(
+                "[E01017] Error uploading '{}' to '{}': {}",
+                file_event.path.to_string_lossy(), &target_name, msg_list.join(": ")
+            );
+
+            return Err(msg_list.join(": "))
+        }
+    };
+
+    info!(
+        "Uploaded '{}' to <{}> '{}' {} bytes",
+        file_event.path.to_string_lossy(), &target_name, &url, bytes_sent
+    );
+
+    metrics::FILE_UPLOAD_COUNTER_VEC
+        .with_label_values(&[&target_name])
+        .inc();
+    metrics::BYTES_UPLOADED_COUNTER_VEC
+        .with_label_values(&[&target_name])
+        .inc_by(bytes_sent);
+
+    let insert_result = persistence.insert_dispatched(&target_name, file_event.file_id).await;
+
+    match insert_result {
+        Ok(_) => debug!("Dispatched to HTTP target"),
+        Err(e) => debug!("Error persisting dispatch: {}", &e)
+    }
+
+    Ok(FileEvent {
+        file_id: file_event.file_id,
+        source_name: target_name,
+        path: PathBuf::from(url)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A request as received by the test server
+    struct Request {
+        request_line: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    /// Serve a single request with the given status and return what was
+    /// received, together with the address to send the request to.
+    async fn serve_once(status: &'static str) -> (String, tokio::task::JoinHandle<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let join_handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut data = Vec::new();
+            let mut buf = [0u8; 4096];
+
+            let header_end = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                data.extend_from_slice(&buf[..n]);
+
+                if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break pos + 4;
+                }
+            };
+
+            let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+            let mut lines = head.split("\r\n");
+            let request_line = lines.next().unwrap().to_string();
+
+            let headers: HashMap<String, String> = lines
+                .filter_map(|line| line.split_once(": "))
+                .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+                .collect();
+
+            let content_length: usize = headers["content-length"].parse().unwrap();
+
+            while data.len() < header_end + content_length {
+                let n = stream.read(&mut buf).await.unwrap();
+                data.extend_from_slice(&buf[..n]);
+            }
+
+            let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+
+            Request { request_line, headers, body: data[header_end..].to_vec() }
+        });
+
+        (address, join_handle)
+    }
+
+    fn uploader(address: &str, method: settings::HttpUploadMethod) -> HttpUploader {
+        let http_target = settings::HttpTarget {
+            name: "test".to_string(),
+            url: format!("http://{}/upload/{{{{ source_name }}}}/{{{{ file_name }}}}", address),
+            method,
+            headers: HashMap::new(),
+            multipart_field: "document".to_string(),
+            timeout: 5_000,
+            thread_count: 1,
+            notify: None,
+        };
+
+        HttpUploader::new(&http_target).unwrap()
+    }
+
+    fn test_file(name: &str, content: &[u8]) -> FileEvent {
+        let path = std::env::temp_dir().join(format!("cortex-http-target-{}-{}", std::process::id(), name));
+
+        std::fs::write(&path, content).unwrap();
+
+        FileEvent { file_id: 1, source_name: "red".to_string(), path }
+    }
+
+    #[tokio::test]
+    async fn put_sends_file_as_body() {
+        let (address, server) = serve_once("201 Created").await;
+        let file_event = test_file("put.csv", b"a,b\n1,2\n");
+
+        let (url, size) = uploader(&address, settings::HttpUploadMethod::Put).upload(&file_event).await.unwrap();
+        let request = server.await.unwrap();
+
+        assert!(request.request_line.starts_with("PUT /upload/red/"));
+        assert!(url.ends_with("put.csv"));
+        assert_eq!(size, 8);
+        assert_eq!(request.headers["content-length"], "8");
+        assert_eq!(request.body, b"a,b\n1,2\n");
+
+        std::fs::remove_file(&file_event.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn post_sends_file_as_multipart_field() {
+        let (address, server) = serve_once("200 OK").await;
+        let file_event = test_file("post.csv", b"x,y\n");
+
+        uploader(&address, settings::HttpUploadMethod::Post).upload(&file_event).await.unwrap();
+        let request = server.await.unwrap();
+        let body = String::from_utf8_lossy(&request.body);
+
+        assert!(request.request_line.starts_with("POST /upload/red/"));
+        assert!(request.headers["content-type"].starts_with("multipart/form-data; boundary="));
+        assert!(body.contains("name=\"document\""));
+        assert!(body.contains("post.csv"));
+        assert!(body.contains("\r\n\r\nx,y\n\r\n"));
+
+        std::fs::remove_file(&file_event.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn error_status_fails_upload() {
+        let (address, server) = serve_once("503 Service Unavailable").await;
+        let file_event = test_file("error.csv", b"z");
+
+        let result = uploader(&address, settings::HttpUploadMethod::Put).upload(&file_event).await;
+        server.await.unwrap();
+
+        match result {
+            Err(Error(ErrorKind::HttpStatusError(503), _)) => (),
+            other => panic!("unexpected upload result: {:?}", other.map(|(url, _)| url)),
+        }
+
+        std::fs::remove_file(&file_event.path).unwrap();
+    }
+}
@@ -58,6 +58,11 @@ where
         }
     }
 
+    /// Directory in which the files of a source are stored
+    pub fn source_directory(&self, source_name: &str) -> PathBuf {
+        self.directory.join(source_name)
+    }
+
     pub fn local_path<P: AsRef<Path>>(
         &self,
         source_name: &str,
@@ -7,18 +7,27 @@ extern crate config;
 extern crate log;
 extern crate env_logger;
 
+mod backfill;
 mod base_types;
 mod cmd;
+mod dead_letter;
+mod deferred_action;
+mod delivery;
 mod dispatcher;
 mod directory_source;
 mod directory_target;
 mod event;
+mod http_downloader;
 mod http_server;
+mod http_target;
 mod metrics;
 mod persistence;
+mod post_download;
 mod settings;
 mod sftp_downloader;
 mod sftp_command_consumer;
+mod sftp_target;
+mod throttle;
 mod local_storage;
 
 use settings::Settings;
@@ -98,10 +107,42 @@ fn main() {
         }
     };
 
+    if let Err(e) = settings.validate() {
+        error!("Invalid configuration: {}", e);
+        ::std::process::exit(1);
+    }
+
     info!("Configuration loaded");
 
+    if let Some(republish_matches) = matches.subcommand_matches("republish-dead-letters") {
+        let source_name = republish_matches.value_of("source").unwrap();
+
+        let limit = match republish_matches.value_of("limit").map(|l| l.parse::<u64>()) {
+            Some(Ok(l)) => Some(l),
+            Some(Err(e)) => {
+                error!("Invalid limit: {}", e);
+                ::std::process::exit(1);
+            },
+            None => None
+        };
+
+        match dead_letter::republish(&settings, source_name, limit) {
+            Ok(count) => {
+                info!("Re-published {} dead-lettered commands of source '{}'", count, source_name);
+                ::std::process::exit(0);
+            },
+            Err(e) => {
+                error!("Error re-publishing dead-lettered commands: {}", e);
+                ::std::process::exit(1);
+            }
+        }
+    }
+
     match dispatcher::run(settings) {
-        Ok(_) => (),
-        Err(e) => error!("{}", e)
+        Ok(_) => info!("Shutdown complete"),
+        Err(e) => {
+            error!("{}", e);
+            ::std::process::exit(1);
+        }
     }
 }
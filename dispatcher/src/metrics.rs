@@ -1,4 +1,4 @@
-use prometheus::IntCounterVec;
+use prometheus::{exponential_buckets, HistogramVec, IntCounterVec, IntGaugeVec};
 
 lazy_static! {
     pub static ref FILE_DOWNLOAD_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
@@ -13,10 +13,113 @@ lazy_static! {
         &["source"]
     )
     .unwrap();
+    pub static ref SFTP_DOWNLOAD_RATE_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "sftp_download_bytes_per_second",
+        "Current combined download throughput of the threads of an SFTP source",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref SFTP_DOWNLOAD_THROUGHPUT_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "sftp_download_throughput_bytes_per_second",
+        "Throughput of SFTP downloads per file, by single or parallel mode",
+        &["source", "mode"],
+        exponential_buckets(65_536.0, 2.0, 14).unwrap()
+    )
+    .unwrap();
+    pub static ref POST_DOWNLOAD_FAILURES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "post_download_failures_total",
+        "Total number of failed actions on remote files after their download",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref REDELIVERED_COMMANDS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "redelivered_commands_total",
+        "Total number of download commands skipped because the file was already downloaded",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref CHECKSUM_MISMATCH_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "checksum_mismatches_total",
+        "Total number of downloaded files that did not match their sidecar checksum",
+        &["source", "action"]
+    )
+    .unwrap();
+    pub static ref DEFERRED_ACTIONS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "deferred_actions",
+        "Number of post-download actions waiting for files to be dispatched, by staleness",
+        &["source", "stale"]
+    )
+    .unwrap();
+    pub static ref FILE_UPLOAD_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "file_upload_total",
+        "Total number of files uploaded to SFTP and HTTP targets",
+        &["target"]
+    )
+    .unwrap();
+    pub static ref BYTES_UPLOADED_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "bytes_upload_total",
+        "Total number of bytes uploaded to SFTP and HTTP targets",
+        &["target"]
+    )
+    .unwrap();
+    pub static ref SFTP_UPLOAD_RATE_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "sftp_upload_bytes_per_second",
+        "Current combined upload throughput of the workers of an SFTP target",
+        &["target"]
+    )
+    .unwrap();
+    pub static ref NOTIFICATIONS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "notifications_total",
+        "Total number of notifications about files placed at targets",
+        &["target", "kind", "outcome"]
+    )
+    .unwrap();
     pub static ref MESSAGES_RECEIVED_COUNTER: IntCounterVec = register_int_counter_vec!(
         "messages_received_total",
         "Total number of messages received",
         &["source"]
     )
     .unwrap();
+    pub static ref AMQP_CONNECTED_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "amqp_connected",
+        "Connection state of the AMQP command consumer of a source (1 = connected)",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref AMQP_RECONNECTS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "amqp_reconnects_total",
+        "Total number of lost AMQP command consumer connections",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref DEAD_LETTERED_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "dead_lettered_total",
+        "Total number of download commands published to the dead-letter exchange",
+        &["source"]
+    )
+    .unwrap();
+    pub static ref DELIVERY_FAILURES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "delivery_failures_total",
+        "Total number of failed deliveries to targets",
+        &["target"]
+    )
+    .unwrap();
+    pub static ref DELIVERY_ATTEMPTS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "delivery_attempts",
+        "Number of failed deliveries per state that are pending retry or dead",
+        &["target", "state"]
+    )
+    .unwrap();
+    pub static ref SFTP_DOWNLOADS_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "sftp_downloads",
+        "Number of recorded SFTP downloads per state",
+        &["source", "state"]
+    )
+    .unwrap();
+    pub static ref BACKFILLED_FILES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "backfilled_files_total",
+        "Total number of undispatched files re-sent to targets",
+        &["target"]
+    )
+    .unwrap();
 }
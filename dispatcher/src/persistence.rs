@@ -7,6 +7,8 @@ use r2d2_postgres::PostgresConnectionManager;
 use tokio_postgres::Socket;
 use chrono::prelude::*;
 
+use crate::settings::DeliveryRetry;
+
 #[derive(Debug)]
 pub struct PersistenceError {
     pub source: Option<Box<dyn error::Error + 'static + Send + Sync>>,
@@ -32,6 +34,68 @@ impl error::Error for PersistenceError {
     }
 }
 
+/// A failed delivery that is due for another attempt
+pub struct DueDelivery {
+    pub file_id: i64,
+    pub target: String,
+    pub source: String,
+    pub path: PathBuf,
+}
+
+/// Everything known about a stored file, as exposed to notification templates
+pub struct FileDetails {
+    pub source: String,
+    pub path: PathBuf,
+    pub modified: DateTime<Utc>,
+    pub size: i64,
+    pub hash: Option<String>,
+    /// Path of the file on the remote SFTP server it was downloaded from
+    pub remote_path: Option<String>,
+}
+
+/// A stored file that was not dispatched to a target yet
+pub struct UndispatchedFile {
+    pub file_id: i64,
+    pub path: PathBuf,
+    pub size: i64,
+    pub modified: DateTime<Utc>,
+}
+
+/// What is known about an earlier download of a command
+pub struct SftpDownloadRecord {
+    pub state: String,
+    pub file_id: Option<i64>,
+    /// Path of the downloaded file in the local storage
+    pub local_path: Option<PathBuf>,
+    /// Size of the downloaded file
+    pub size: Option<i64>,
+    /// Modification time of the remote file
+    pub modified: Option<DateTime<Utc>>,
+    /// Targets the downloaded file has been dispatched to
+    pub dispatched: Vec<String>,
+    /// No post-download action was executed, failed or deferred yet
+    pub post_download_pending: bool,
+}
+
+/// A post-download action that waits for the file to be dispatched
+pub struct DeferredAction {
+    pub sftp_download_id: i64,
+    /// Path of the file on the remote SFTP server
+    pub remote_path: String,
+    /// The action, serialized as JSON
+    pub action: String,
+    /// Path of the downloaded file in the local storage
+    pub local_path: PathBuf,
+    /// Size of the downloaded file
+    pub size: i64,
+    /// Modification time of the remote file
+    pub modified: DateTime<Utc>,
+    /// Targets the file has been dispatched to
+    pub dispatched: Vec<String>,
+    /// Milliseconds since the action was deferred
+    pub age: i64,
+}
+
 pub struct FileInfo {
     source: String,
     path: PathBuf,
@@ -40,11 +104,51 @@ pub struct FileInfo {
     hash: Option<String>
 }
 
+/// Lifecycle states of a record in dispatcher.sftp_download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SftpDownloadState {
+    /// Inserted by the scanner, the download command is on its way
+    Queued,
+    Downloading,
+    Downloaded,
+    /// The download failed permanently or was dropped
+    Failed,
+    /// The remote file no longer existed when it was downloaded
+    Vanished,
+}
+
+impl SftpDownloadState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SftpDownloadState::Queued => "queued",
+            SftpDownloadState::Downloading => "downloading",
+            SftpDownloadState::Downloaded => "downloaded",
+            SftpDownloadState::Failed => "failed",
+            SftpDownloadState::Vanished => "vanished",
+        }
+    }
+}
+
+impl fmt::Display for SftpDownloadState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 pub trait Persistence {
     fn insert_sftp_download(&self, source: &str, path: &str, size: i64) -> Result<i64, PersistenceError>;
-    fn delete_sftp_download_file(&self, id: i64) -> Result<(), PersistenceError>;
-    fn set_sftp_download_file(&self, id: i64, file_id: i64) -> Result<(), PersistenceError>;
+    fn get_sftp_download(&self, id: i64) -> Result<Option<SftpDownloadRecord>, PersistenceError>;
+    fn set_sftp_download_state(&self, id: i64, state: SftpDownloadState, error: Option<&str>) -> Result<(), PersistenceError>;
+    fn set_sftp_download_file(&self, id: i64, file_id: i64, state: SftpDownloadState) -> Result<(), PersistenceError>;
+    fn set_sftp_download_post_action(&self, id: i64, outcome: Option<&str>, error: Option<&str>) -> Result<(), PersistenceError>;
+    fn set_sftp_download_marker(&self, id: i64, outcome: Option<&str>, error: Option<&str>) -> Result<(), PersistenceError>;
+    fn insert_deferred_action(&self, sftp_download_id: i64, action: &str) -> Result<(), PersistenceError>;
+    fn schedule_delivery(&self, target: &str, file_id: i64, reason: &str) -> Result<(), PersistenceError>;
+    fn deferred_actions(&self, source: &str) -> Result<Vec<DeferredAction>, PersistenceError>;
+    fn remove_deferred_action(&self, sftp_download_id: i64) -> Result<(), PersistenceError>;
+    fn record_sftp_download_attempt(&self, id: i64, error: &str) -> Result<i32, PersistenceError>;
     fn insert_file(&self, source: &str, path: &str, modified: &DateTime<Utc>, size: i64, hash: Option<String>) -> Result<i64,PersistenceError>;
+    fn set_file_checksum(&self, file_id: i64, checksum: &str, verification: &str) -> Result<(), PersistenceError>;
     fn remove_file(&self, source: &str, path: &str) -> Result<(),PersistenceError>;
     fn get_file(&self, source: &str, path: &str) -> Result<Option<FileInfo>,PersistenceError>;
     fn insert_dispatched(&self, dest: &str, file_id: i64) -> Result<(), PersistenceError>;
@@ -86,8 +190,8 @@ where
         let mut client = self.conn_pool.get().unwrap();
 
         let execute_result = client.query_one(
-            "insert into dispatcher.sftp_download (source, path, size) values ($1, $2, $3) returning id",
-            &[&source, &path, &size]
+            "insert into dispatcher.sftp_download (source, path, size, state) values ($1, $2, $3, $4) returning id",
+            &[&source, &path, &size, &SftpDownloadState::Queued.as_str()]
         );
 
         match execute_result {
@@ -101,12 +205,14 @@ where
         }
     }
 
-    fn set_sftp_download_file(&self, id: i64, file_id: i64) -> Result<(), PersistenceError> {
+    /// Link the downloaded file to the download record and move the record to
+    /// `state`, 'downloaded' unless the file was quarantined
+    fn set_sftp_download_file(&self, id: i64, file_id: i64, state: SftpDownloadState) -> Result<(), PersistenceError> {
         let mut client = self.conn_pool.get().unwrap();
 
         let execute_result = client.execute(
-            "update dispatcher.sftp_download set file_id = $2 where id = $1",
-            &[&id, &file_id]
+            "update dispatcher.sftp_download set file_id = $2, state = $3, state_changed = now() where id = $1",
+            &[&id, &file_id, &state.as_str()]
         );
 
         match execute_result {
@@ -120,20 +226,241 @@ where
         }
     }
 
-    fn delete_sftp_download_file(&self, id: i64) -> Result<(), PersistenceError> {
+    /// Record the outcome of the action on the remote file after the
+    /// download, or the error when it failed.
+    fn set_sftp_download_post_action(&self, id: i64, outcome: Option<&str>, error: Option<&str>) -> Result<(), PersistenceError> {
+        let mut client = self.conn_pool.get().unwrap();
+
+        let execute_result = client.execute(
+            "update dispatcher.sftp_download set post_download = $2, post_download_error = $3 where id = $1",
+            &[&id, &outcome, &error]
+        );
+
+        match execute_result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                Err(PersistenceError{
+                    source: Some(Box::new(e)),
+                    message: String::from("Error recording post-download action in database")
+                })
+            }
+        }
+    }
+
+    /// Record what was done with the completion marker of the downloaded
+    /// file, or the error when that failed.
+    fn set_sftp_download_marker(&self, id: i64, outcome: Option<&str>, error: Option<&str>) -> Result<(), PersistenceError> {
+        let mut client = self.conn_pool.get().unwrap();
+
+        let execute_result = client.execute(
+            "update dispatcher.sftp_download set marker = $2, marker_error = $3 where id = $1",
+            &[&id, &outcome, &error]
+        );
+
+        match execute_result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                Err(PersistenceError{
+                    source: Some(Box::new(e)),
+                    message: String::from("Error recording marker action in database")
+                })
+            }
+        }
+    }
+
+    fn insert_deferred_action(&self, sftp_download_id: i64, action: &str) -> Result<(), PersistenceError> {
+        let mut client = self.conn_pool.get().unwrap();
+
+        let execute_result = client.execute(
+            "insert into dispatcher.deferred_action (sftp_download_id, action) values ($1, $2) \
+            on conflict (sftp_download_id) do update set action = excluded.action",
+            &[&sftp_download_id, &action]
+        );
+
+        match execute_result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                Err(PersistenceError{
+                    source: Some(Box::new(e)),
+                    message: String::from("Error inserting deferred action into database")
+                })
+            }
+        }
+    }
+
+    /// Schedule a delivery of a stored file to a target through the retry
+    /// mechanism of failed deliveries, unless one is already scheduled.
+    fn schedule_delivery(&self, target: &str, file_id: i64, reason: &str) -> Result<(), PersistenceError> {
+        let mut client = self.conn_pool.get().unwrap();
+
+        let execute_result = client.execute(
+            "insert into dispatcher.delivery_attempt (file_id, target, attempts, state, next_attempt, last_error) \
+            values ($1, $2, 0, 'pending', now(), $3) \
+            on conflict (file_id, target) do nothing",
+            &[&file_id, &target, &reason]
+        );
+
+        match execute_result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                Err(PersistenceError{
+                    source: Some(Box::new(e)),
+                    message: String::from("Error scheduling delivery in database")
+                })
+            }
+        }
+    }
+
+    /// Pending deferred actions of a source, oldest first, with the targets
+    /// each file has been dispatched to.
+    fn deferred_actions(&self, source: &str) -> Result<Vec<DeferredAction>, PersistenceError> {
+        let mut client = self.conn_pool.get().unwrap();
+
+        let query_result = client.query(
+            "select deferred_action.sftp_download_id, sftp_download.path, deferred_action.action, file.path, \
+            coalesce(array_agg(dispatched.target) filter (where dispatched.target is not null), '{}'), \
+            (extract(epoch from now() - deferred_action.timestamp) * 1000)::bigint, \
+            file.size, file.modified \
+            from dispatcher.deferred_action \
+            join dispatcher.sftp_download on sftp_download.id = deferred_action.sftp_download_id \
+            join dispatcher.file on file.id = sftp_download.file_id \
+            left join dispatcher.dispatched on dispatched.file_id = file.id \
+            where sftp_download.source = $1 \
+            group by deferred_action.sftp_download_id, sftp_download.path, deferred_action.action, file.id, deferred_action.timestamp \
+            order by deferred_action.timestamp",
+            &[&source]
+        );
+
+        match query_result {
+            Ok(rows) => Ok(rows.iter().map(|row| {
+                let local_path: String = row.get(3);
+
+                DeferredAction {
+                    sftp_download_id: row.get(0),
+                    remote_path: row.get(1),
+                    action: row.get(2),
+                    local_path: PathBuf::from(local_path),
+                    size: row.get(6),
+                    modified: row.get(7),
+                    dispatched: row.get(4),
+                    age: row.get(5),
+                }
+            }).collect()),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error reading deferred actions from database")
+            })
+        }
+    }
+
+    fn remove_deferred_action(&self, sftp_download_id: i64) -> Result<(), PersistenceError> {
         let mut client = self.conn_pool.get().unwrap();
 
         let execute_result = client.execute(
-            "delete from dispatcher.sftp_download where id = $1",
+            "delete from dispatcher.deferred_action where sftp_download_id = $1",
+            &[&sftp_download_id]
+        );
+
+        match execute_result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                Err(PersistenceError{
+                    source: Some(Box::new(e)),
+                    message: String::from("Error removing deferred action from database")
+                })
+            }
+        }
+    }
+
+    /// Record a failed download attempt with its error and return the total
+    /// number of failed attempts, including those of earlier deliveries of
+    /// the same command.
+    fn record_sftp_download_attempt(&self, id: i64, error: &str) -> Result<i32, PersistenceError> {
+        let mut client = self.conn_pool.get().unwrap();
+
+        let execute_result = client.query_opt(
+            "update dispatcher.sftp_download set attempts = attempts + 1, last_error = $2 where id = $1 returning attempts",
+            &[&id, &error]
+        );
+
+        match execute_result {
+            Ok(Some(row)) => Ok(row.get(0)),
+            Ok(None) => {
+                Err(PersistenceError{
+                    source: None,
+                    message: format!("No sftp_download record with id {}", id)
+                })
+            },
+            Err(e) => {
+                Err(PersistenceError{
+                    source: Some(Box::new(e)),
+                    message: String::from("Error recording sftp_download attempt in database")
+                })
+            }
+        }
+    }
+
+    fn get_sftp_download(&self, id: i64) -> Result<Option<SftpDownloadRecord>, PersistenceError> {
+        let mut client = self.conn_pool.get().unwrap();
+
+        let query_result = client.query_opt(
+            "select sftp_download.state, sftp_download.file_id, file.path, \
+            coalesce(array_agg(dispatched.target) filter (where dispatched.target is not null), '{}'), \
+            sftp_download.post_download is null and sftp_download.post_download_error is null \
+            and not exists (select 1 from dispatcher.deferred_action where deferred_action.sftp_download_id = sftp_download.id), \
+            file.size, file.modified \
+            from dispatcher.sftp_download \
+            left join dispatcher.file on file.id = sftp_download.file_id \
+            left join dispatcher.dispatched on dispatched.file_id = file.id \
+            where sftp_download.id = $1 \
+            group by sftp_download.id, file.id",
             &[&id]
         );
 
+        match query_result {
+            Ok(row) => Ok(row.map(|row| {
+                let local_path: Option<String> = row.get(2);
+
+                SftpDownloadRecord {
+                    state: row.get(0),
+                    file_id: row.get(1),
+                    local_path: local_path.map(PathBuf::from),
+                    size: row.get(5),
+                    modified: row.get(6),
+                    dispatched: row.get(3),
+                    post_download_pending: row.get(4),
+                }
+            })),
+            Err(e) => {
+                Err(PersistenceError{
+                    source: Some(Box::new(e)),
+                    message: String::from("Error reading sftp_download record from database")
+                })
+            }
+        }
+    }
+
+    /// Move a download record to a new state. The error, when specified,
+    /// replaces the last error of the record. Moving a record back to
+    /// 'queued' resets its attempt count, so that a requeued or re-published
+    /// command gets all its attempts again.
+    fn set_sftp_download_state(&self, id: i64, state: SftpDownloadState, error: Option<&str>) -> Result<(), PersistenceError> {
+        let mut client = self.conn_pool.get().unwrap();
+
+        let execute_result = client.execute(
+            "update dispatcher.sftp_download set state = $2, state_changed = now(), last_error = coalesce($3, last_error), \
+            attempts = case when $2 = 'queued' then 0 else attempts end, \
+            download_started = case when $2 = 'downloading' then now() else download_started end \
+            where id = $1",
+            &[&id, &state.as_str(), &error]
+        );
+
         match execute_result {
             Ok(_) => Ok(()),
             Err(e) => {
                 Err(PersistenceError{
                     source: Some(Box::new(e)),
-                    message: String::from("Error deleting sftp_download record from database")
+                    message: format!("Error setting sftp_download record state to '{}'", state)
                 })
             }
         }
@@ -156,6 +483,24 @@ where
         }
     }
 
+    /// Record the sidecar checksum of a file and the outcome of its verification
+    fn set_file_checksum(&self, file_id: i64, checksum: &str, verification: &str) -> Result<(), PersistenceError> {
+        let mut client = self.conn_pool.get().unwrap();
+
+        let execute_result = client.execute(
+            "update dispatcher.file set checksum = $2, checksum_verification = $3 where id = $1",
+            &[&file_id, &checksum, &verification]
+        );
+
+        match execute_result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error updating file checksum in database")
+            })
+        }
+    }
+
     fn remove_file(&self, source: &str, path: &str) -> Result<(),PersistenceError> {
         let mut client = self.conn_pool.get().unwrap();
 
@@ -270,6 +615,229 @@ where
         PostgresAsyncPersistence { conn_pool: pool }
     }
 
+    async fn get_client(&self) -> Result<bb8::PooledConnection<'_, bb8_postgres::PostgresConnectionManager<T>>, PersistenceError> {
+        self.conn_pool.get().await.map_err(|e| {
+            let message = format!("Error getting PostgreSQL conection from pool: {}", &e);
+            error!("{}", &message);
+
+            PersistenceError{
+                source: Some(Box::new(e)),
+                message
+            }
+        })
+    }
+
+    /// Register a failed delivery of a file to a target and schedule the next
+    /// attempt with exponential backoff. Returns the number of attempts so far
+    /// and the resulting state ('pending' or 'dead').
+    pub async fn record_delivery_failure(&self, target: &str, file_id: i64, error: &str, policy: &DeliveryRetry) -> Result<(i32, String), PersistenceError> {
+        let client = self.get_client().await?;
+
+        let initial_delay = policy.initial_delay as f64;
+        let max_delay = policy.max_delay as f64;
+
+        let upsert_result = client.query_one(
+            "insert into dispatcher.delivery_attempt (file_id, target, attempts, state, next_attempt, last_error) \
+            values ($1, $2, 1, case when $6::integer <= 1 then 'dead' else 'pending' end, now() + least($5::float8, $4::float8) * interval '1 millisecond', $3) \
+            on conflict (file_id, target) do update set \
+            attempts = delivery_attempt.attempts + 1, \
+            state = case when delivery_attempt.attempts + 1 >= $6::integer then 'dead' else 'pending' end, \
+            next_attempt = now() + least($5::float8, $4::float8 * power(2, delivery_attempt.attempts)) * interval '1 millisecond', \
+            last_error = excluded.last_error, \
+            timestamp = now() \
+            returning attempts, state",
+            &[&file_id, &target, &error, &initial_delay, &max_delay, &policy.max_attempts]
+        ).await;
+
+        match upsert_result {
+            Ok(row) => Ok((row.get(0), row.get(1))),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error recording delivery failure in database")
+            })
+        }
+    }
+
+    /// Remove any pending or dead delivery record after a successful delivery.
+    pub async fn clear_delivery_attempt(&self, target: &str, file_id: i64) -> Result<(), PersistenceError> {
+        let client = self.get_client().await?;
+
+        let delete_result = client.execute(
+            "delete from dispatcher.delivery_attempt where file_id = $1 and target = $2",
+            &[&file_id, &target]
+        ).await;
+
+        match delete_result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error removing delivery attempt from database")
+            })
+        }
+    }
+
+    /// Claim pending deliveries that are due for a retry. The next attempt of
+    /// claimed deliveries is postponed by `claim_delay` milliseconds, so that
+    /// they are not picked up again while the retry is in progress.
+    pub async fn claim_due_deliveries(&self, claim_delay: u64, limit: i64) -> Result<Vec<DueDelivery>, PersistenceError> {
+        let client = self.get_client().await?;
+
+        let claim_delay = claim_delay as f64;
+
+        let query_result = client.query(
+            "with due as (\
+                select file_id, target from dispatcher.delivery_attempt \
+                where state = 'pending' and next_attempt <= now() \
+                order by next_attempt limit $2 for update skip locked\
+            ) \
+            update dispatcher.delivery_attempt da set next_attempt = now() + $1::float8 * interval '1 millisecond' \
+            from due, dispatcher.file f \
+            where da.file_id = due.file_id and da.target = due.target and f.id = da.file_id \
+            returning da.file_id, da.target, f.source, f.path",
+            &[&claim_delay, &limit]
+        ).await;
+
+        match query_result {
+            Ok(rows) => Ok(rows.iter().map(|row| {
+                let path: String = row.get(3);
+
+                DueDelivery {
+                    file_id: row.get(0),
+                    target: row.get(1),
+                    source: row.get(2),
+                    path: PathBuf::from(path),
+                }
+            }).collect()),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error reading due deliveries from database")
+            })
+        }
+    }
+
+    /// Put a download record back in the 'queued' state with its attempt
+    /// count reset, for a command that is re-published.
+    pub async fn requeue_sftp_download(&self, id: i64) -> Result<(), PersistenceError> {
+        let client = self.get_client().await?;
+
+        let update_result = client.execute(
+            "update dispatcher.sftp_download set state = 'queued', state_changed = now(), attempts = 0 where id = $1",
+            &[&id]
+        ).await;
+
+        match update_result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error requeueing sftp_download record in database")
+            })
+        }
+    }
+
+    /// Number of download records per source and state
+    pub async fn sftp_download_state_counts(&self) -> Result<Vec<(String, String, i64)>, PersistenceError> {
+        let client = self.get_client().await?;
+
+        let query_result = client.query(
+            "select source, state, count(*) from dispatcher.sftp_download group by source, state",
+            &[]
+        ).await;
+
+        match query_result {
+            Ok(rows) => Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect()),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error reading download state counts from database")
+            })
+        }
+    }
+
+    /// Number of failed deliveries per target and state
+    pub async fn delivery_attempt_counts(&self) -> Result<Vec<(String, String, i64)>, PersistenceError> {
+        let client = self.get_client().await?;
+
+        let query_result = client.query(
+            "select target, state, count(*) from dispatcher.delivery_attempt group by target, state",
+            &[]
+        ).await;
+
+        match query_result {
+            Ok(rows) => Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect()),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error reading delivery attempt counts from database")
+            })
+        }
+    }
+
+    /// Files of a source that have not been dispatched to a target and were
+    /// stored at least `grace_interval` milliseconds ago. Files with a
+    /// pending or dead delivery attempt are left to the retry mechanism, and
+    /// quarantined files are never dispatched.
+    pub async fn undispatched_files(&self, source: &str, target: &str, grace_interval: u64) -> Result<Vec<UndispatchedFile>, PersistenceError> {
+        let client = self.get_client().await?;
+
+        let grace_interval = grace_interval as f64;
+
+        let query_result = client.query(
+            "select file.id, file.path, file.size, file.modified from dispatcher.file \
+            where file.id in (select dispatcher.undispatched_files($1, $2, $3::float8 * interval '1 millisecond')) \
+            and not exists (select 1 from dispatcher.delivery_attempt da where da.file_id = file.id and da.target = $2) \
+            and file.checksum_verification is distinct from 'mismatch' \
+            order by file.id",
+            &[&source, &target, &grace_interval]
+        ).await;
+
+        match query_result {
+            Ok(rows) => Ok(rows.iter().map(|row| {
+                let path: String = row.get(1);
+
+                UndispatchedFile {
+                    file_id: row.get(0),
+                    path: PathBuf::from(path),
+                    size: row.get(2),
+                    modified: row.get(3),
+                }
+            }).collect()),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error reading undispatched files from database")
+            })
+        }
+    }
+
+    pub async fn file_details(&self, file_id: i64) -> Result<Option<FileDetails>, PersistenceError> {
+        let client = self.get_client().await?;
+
+        let query_result = client.query_opt(
+            "select f.source, f.path, f.modified, f.size, f.hash, sd.path \
+            from dispatcher.file f \
+            left join dispatcher.sftp_download sd on sd.file_id = f.id \
+            where f.id = $1 \
+            limit 1",
+            &[&file_id]
+        ).await;
+
+        match query_result {
+            Ok(row) => Ok(row.map(|row| {
+                let path: String = row.get(1);
+
+                FileDetails {
+                    source: row.get(0),
+                    path: PathBuf::from(path),
+                    modified: row.get(2),
+                    size: row.get(3),
+                    hash: row.get(4),
+                    remote_path: row.get(5),
+                }
+            })),
+            Err(e) => Err(PersistenceError{
+                source: Some(Box::new(e)),
+                message: String::from("Error reading file record from database")
+            })
+        }
+    }
+
     pub async fn insert_dispatched(&self, dest: &str, file_id: i64) -> Result<(), PersistenceError> {
         let get_result = self.conn_pool.get().await;
 
@@ -299,4 +867,132 @@ where
             })
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The database tests run against the schema in db/schema.sql, in the
+    /// database referenced by CORTEX_TEST_DATABASE_URL, and are skipped when
+    /// that variable is not set.
+    async fn test_persistence() -> Option<PostgresAsyncPersistence<tokio_postgres::NoTls>> {
+        let url = match std::env::var("CORTEX_TEST_DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("CORTEX_TEST_DATABASE_URL not set, skipping database test");
+                return None;
+            }
+        };
+
+        let connection_manager = bb8_postgres::PostgresConnectionManager::new(url.parse().unwrap(), tokio_postgres::NoTls);
+
+        Some(PostgresAsyncPersistence::new(connection_manager).await)
+    }
+
+    fn test_sync_persistence() -> Option<PostgresPersistence<postgres::NoTls>> {
+        let url = std::env::var("CORTEX_TEST_DATABASE_URL").ok()?;
+
+        Some(PostgresPersistence::new(PostgresConnectionManager::new(url.parse().unwrap(), postgres::NoTls)))
+    }
+
+    async fn insert_test_file(persistence: &PostgresAsyncPersistence<tokio_postgres::NoTls>) -> i64 {
+        let client = persistence.get_client().await.unwrap();
+
+        let row = client.query_one(
+            "insert into dispatcher.file (source, path, modified, size) values ('test', '/tmp/test-file', now(), 0) returning id",
+            &[]
+        ).await.unwrap();
+
+        row.get(0)
+    }
+
+    #[tokio::test]
+    async fn delivery_failures_back_off_until_dead() {
+        let persistence = match test_persistence().await {
+            Some(persistence) => persistence,
+            None => return,
+        };
+
+        let file_id = insert_test_file(&persistence).await;
+
+        let policy = DeliveryRetry {
+            initial_delay: 1_000,
+            max_delay: 3_000,
+            max_attempts: 3,
+            interval: 1_000,
+        };
+
+        let first = persistence.record_delivery_failure("test", file_id, "first", &policy).await.unwrap();
+        let second = persistence.record_delivery_failure("test", file_id, "second", &policy).await.unwrap();
+        let third = persistence.record_delivery_failure("test", file_id, "third", &policy).await.unwrap();
+
+        assert_eq!(first, (1, String::from("pending")));
+        assert_eq!(second, (2, String::from("pending")));
+        assert_eq!(third, (3, String::from("dead")));
+
+        let client = persistence.get_client().await.unwrap();
+
+        let row = client.query_one(
+            "select extract(epoch from next_attempt - timestamp)::float8, last_error from dispatcher.delivery_attempt where file_id = $1 and target = 'test'",
+            &[&file_id]
+        ).await.unwrap();
+
+        let delay: f64 = row.get(0);
+        let last_error: String = row.get(1);
+
+        assert!((delay - 3.0).abs() < 0.5, "delay {} is not capped at max_delay", delay);
+        assert_eq!(last_error, "third");
+
+        persistence.clear_delivery_attempt("test", file_id).await.unwrap();
+        client.execute("delete from dispatcher.file where id = $1", &[&file_id]).await.unwrap();
+    }
+
+    #[test]
+    fn queued_downloads_get_all_attempts_again() {
+        let persistence = match test_sync_persistence() {
+            Some(persistence) => persistence,
+            None => return,
+        };
+
+        let id = persistence.insert_sftp_download("test", "/upload/a.csv", 10).unwrap();
+
+        assert_eq!(persistence.record_sftp_download_attempt(id, "first").unwrap(), 1);
+        assert_eq!(persistence.record_sftp_download_attempt(id, "second").unwrap(), 2);
+
+        persistence.set_sftp_download_state(id, SftpDownloadState::Failed, Some("second")).unwrap();
+        assert_eq!(persistence.record_sftp_download_attempt(id, "third").unwrap(), 3);
+
+        persistence.set_sftp_download_state(id, SftpDownloadState::Queued, None).unwrap();
+        assert_eq!(persistence.record_sftp_download_attempt(id, "fourth").unwrap(), 1);
+
+        let mut client = persistence.conn_pool.get().unwrap();
+        client.execute("delete from dispatcher.sftp_download where id = $1", &[&id]).unwrap();
+    }
+
+    #[test]
+    fn post_download_is_pending_until_executed_or_deferred() {
+        let persistence = match test_sync_persistence() {
+            Some(persistence) => persistence,
+            None => return,
+        };
+
+        let pending = |id| persistence.get_sftp_download(id).unwrap().unwrap().post_download_pending;
+
+        let executed = persistence.insert_sftp_download("test", "/upload/a.csv", 10).unwrap();
+        let deferred = persistence.insert_sftp_download("test", "/upload/b.csv", 10).unwrap();
+        let failed = persistence.insert_sftp_download("test", "/upload/c.csv", 10).unwrap();
+
+        assert!(pending(executed) && pending(deferred) && pending(failed));
+
+        persistence.set_sftp_download_post_action(executed, Some("removed"), None).unwrap();
+        persistence.insert_deferred_action(deferred, "\"Delete\"").unwrap();
+        persistence.set_sftp_download_post_action(failed, None, Some("permission denied")).unwrap();
+
+        assert!(!pending(executed) && !pending(deferred) && !pending(failed));
+
+        persistence.remove_deferred_action(deferred).unwrap();
+
+        let mut client = persistence.conn_pool.get().unwrap();
+        client.execute("delete from dispatcher.sftp_download where id = any($1)", &[&vec![executed, deferred, failed]]).unwrap();
+    }
+}
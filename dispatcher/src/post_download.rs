@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use tera::{Context, Tera};
+
+use cortex_core::PostDownloadAction;
+use cortex_core::ssh2;
+
+error_chain! {}
+
+/// Permissions of directories created for moved files
+const DIRECTORY_MODE: i32 = 0o755;
+
+const RENAME_TEMPLATE_NAME: &str = "rename";
+
+/// Perform the post-download action on a remote file and describe what was
+/// done, or return None when there was nothing to do.
+pub fn execute(sftp: &ssh2::Sftp, action: &PostDownloadAction, remote_path: &Path) -> Result<Option<String>> {
+    match action {
+        PostDownloadAction::None => Ok(None),
+        PostDownloadAction::Delete => {
+            sftp.unlink(remote_path)
+                .chain_err(|| format!("Error removing '{}'", remote_path.to_string_lossy()))?;
+
+            Ok(Some("removed".to_string()))
+        },
+        PostDownloadAction::Rename { template } => {
+            let target_path = rename_target(template, remote_path)?;
+
+            rename(sftp, remote_path, &target_path)?;
+
+            Ok(Some(format!("renamed to '{}'", target_path.to_string_lossy())))
+        },
+        PostDownloadAction::Move { directory } => {
+            let target_directory = resolve(remote_path, Path::new(directory));
+
+            create_dir_all(sftp, &target_directory)?;
+
+            let file_name = remote_path.file_name()
+                .chain_err(|| format!("No file name in remote path '{}'", remote_path.to_string_lossy()))?;
+
+            let target_path = target_directory.join(file_name);
+
+            rename(sftp, remote_path, &target_path)?;
+
+            Ok(Some(format!("moved to '{}'", target_path.to_string_lossy())))
+        }
+    }
+}
+
+/// Render the rename template for a remote file. Available in the template
+/// are `file_name`, `file_stem`, `extension` and `timestamp`, the current
+/// time formatted as '%Y%m%dT%H%M%S'.
+fn rename_target(template: &str, remote_path: &Path) -> Result<PathBuf> {
+    let mut tera = Tera::default();
+
+    tera.add_raw_template(RENAME_TEMPLATE_NAME, template)
+        .chain_err(|| format!("Invalid rename template '{}'", template))?;
+
+    let lossy = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+
+    let mut context = Context::new();
+    context.insert("file_name", &lossy(remote_path.file_name()));
+    context.insert("file_stem", &lossy(remote_path.file_stem()));
+    context.insert("extension", &lossy(remote_path.extension()));
+    context.insert("timestamp", &Utc::now().format("%Y%m%dT%H%M%S").to_string());
+
+    let rendered = tera.render(RENAME_TEMPLATE_NAME, &context)
+        .chain_err(|| "Error rendering rename template")?;
+
+    if rendered.is_empty() {
+        bail!("Rename template rendered an empty name");
+    }
+
+    Ok(resolve(remote_path, Path::new(&rendered)))
+}
+
+/// Resolve a path relative to the directory of a remote file
+fn resolve(remote_path: &Path, path: &Path) -> PathBuf {
+    match remote_path.parent() {
+        Some(parent) if path.is_relative() => parent.join(path),
+        _ => path.to_path_buf()
+    }
+}
+
+fn rename(sftp: &ssh2::Sftp, from: &Path, to: &Path) -> Result<()> {
+    sftp.rename(from, to, None)
+        .chain_err(|| format!("Error renaming '{}' to '{}'", from.to_string_lossy(), to.to_string_lossy()))
+}
+
+fn create_dir_all(sftp: &ssh2::Sftp, directory: &Path) -> Result<()> {
+    if directory.as_os_str().is_empty() || sftp.stat(directory).is_ok() {
+        return Ok(())
+    }
+
+    if let Some(parent) = directory.parent() {
+        create_dir_all(sftp, parent)?;
+    }
+
+    sftp.mkdir(directory, DIRECTORY_MODE)
+        .chain_err(|| format!("Error creating directory '{}'", directory.to_string_lossy()))
+}
@@ -1,48 +1,14 @@
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::path::PathBuf;
 
-use regex::Regex;
+use chrono::NaiveTime;
+
+use cortex_core::HostKeyPolicy;
 
 #[cfg(target_os = "linux")]
 use inotify::WatchMask;
 
-extern crate regex;
-extern crate serde_regex;
-
-trait FileFilter {
-    fn file_matches<P: AsRef<Path>>(&self, path: P) -> bool;
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct RegexFilter {
-    #[serde(with = "serde_regex")]
-    pattern: Regex,
-}
-
-impl FileFilter for RegexFilter {
-    fn file_matches<P: AsRef<Path>>(&self, path: P) -> bool {
-        let file_name_result = path.as_ref().file_name();
-
-        file_name_result.map_or_else(
-            || false,
-            |file_name| self.pattern.is_match(file_name.to_str().unwrap()),
-        )
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum Filter {
-    Regex(RegexFilter),
-    All,
-}
-
-impl Filter {
-    pub fn file_matches<P: AsRef<Path>>(&self, path: P) -> bool {
-        match self {
-            Filter::Regex(r) => r.file_matches(path),
-            Filter::All => true,
-        }
-    }
-}
+pub use cortex_core::filter::Filter;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Connection {
@@ -104,12 +70,60 @@ pub struct RabbitMQNotify {
     pub address: String,
     pub exchange: String,
     pub routing_key: String,
+    #[serde(default = "default_notify_content_type")]
+    pub content_type: String,
+    /// AMQP message headers, with values that are templates rendered with the
+    /// same context as the message, e.g. `file_id: "{{ file_id }}"`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Number of retries after a failed or unconfirmed publish
+    #[serde(default = "default_notify_retries")]
+    pub retries: u32,
+    /// Delay in milliseconds between retries
+    #[serde(default = "default_notify_retry_delay")]
+    pub retry_delay: u64,
+}
+
+fn default_notify_content_type() -> String {
+    "application/json".to_string()
+}
+
+/// Notification by an HTTP POST request to a consumer
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookNotify {
+    pub url: String,
+    pub message_template: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Timeout in milliseconds for one request
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: u64,
+    /// Number of retries after a failed request
+    #[serde(default = "default_notify_retries")]
+    pub retries: u32,
+    /// Delay in milliseconds between retries
+    #[serde(default = "default_notify_retry_delay")]
+    pub retry_delay: u64,
+}
+
+fn default_webhook_timeout() -> u64 {
+    10_000
+}
+
+fn default_notify_retries() -> u32 {
+    3
+}
+
+fn default_notify_retry_delay() -> u64 {
+    1_000
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Notify {
     #[serde(rename = "rabbitmq")]
     RabbitMQ(RabbitMQNotify),
+    #[serde(rename = "webhook")]
+    Webhook(WebhookNotify),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -134,6 +148,83 @@ fn default_local_target_method() -> LocalTargetMethod {
     LocalTargetMethod::Hardlink
 }
 
+/// Remote directory on an SFTP server that files are pushed to
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SftpTarget {
+    pub name: String,
+    pub address: String,
+    pub username: String,
+    pub password: Option<String>,
+    pub key_file: Option<PathBuf>,
+    #[serde(default = "default_false")]
+    pub compress: bool,
+    /// OpenSSH known_hosts file used to verify the server host key
+    pub known_hosts: Option<PathBuf>,
+    #[serde(default)]
+    pub host_key_fingerprints: Vec<String>,
+    /// Strict by default when known_hosts or host_key_fingerprints are
+    /// configured, off otherwise
+    pub host_key_policy: Option<HostKeyPolicy>,
+    /// Remote directory the files are uploaded to
+    pub directory: PathBuf,
+    /// Permissions set on the uploaded files, e.g. 0o644
+    pub permissions: Option<u32>,
+    /// Replace existing remote files. When disabled, an existing file of the
+    /// same size counts as delivered, and one of another size fails the
+    /// delivery, which is retried.
+    #[serde(default = "default_false")]
+    pub overwrite: bool,
+    /// Number of concurrent uploads, each with its own connection
+    #[serde(default = "default_thread_count")]
+    pub thread_count: usize,
+    pub notify: Option<Notify>,
+    /// Combined upload limit of all threads of the target, unlimited when
+    /// not specified
+    pub max_bytes_per_second: Option<u64>,
+    /// Limits for times of the day that override `max_bytes_per_second`
+    #[serde(default)]
+    pub bandwidth_schedule: Vec<BandwidthWindow>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum HttpUploadMethod {
+    /// PUT request with the file as body
+    Put,
+    /// POST request with the file as multipart form field
+    Post,
+}
+
+/// HTTP endpoint that files are uploaded to
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpTarget {
+    pub name: String,
+    /// Tera template of the upload URL, with the variables file_name,
+    /// file_path, file_id and source_name
+    pub url: String,
+    #[serde(default = "default_http_upload_method")]
+    pub method: HttpUploadMethod,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Name of the form field for multipart POST uploads
+    #[serde(default = "default_multipart_field")]
+    pub multipart_field: String,
+    /// Timeout in milliseconds for a complete upload
+    #[serde(default = "default_http_timeout")]
+    pub timeout: u64,
+    /// Number of concurrent uploads
+    #[serde(default = "default_thread_count")]
+    pub thread_count: usize,
+    pub notify: Option<Notify>,
+}
+
+fn default_http_upload_method() -> HttpUploadMethod {
+    HttpUploadMethod::Put
+}
+
+fn default_multipart_field() -> String {
+    "file".to_string()
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SftpSource {
     pub name: String,
@@ -145,6 +236,314 @@ pub struct SftpSource {
     pub thread_count: usize,
     #[serde(default = "default_false")]
     pub compress: bool,
+    /// OpenSSH known_hosts file used to verify the server host key
+    pub known_hosts: Option<PathBuf>,
+    /// Pinned host key fingerprints, e.g. 'SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8'
+    #[serde(default)]
+    pub host_key_fingerprints: Vec<String>,
+    /// Strict by default when known_hosts or host_key_fingerprints are
+    /// configured, off otherwise
+    pub host_key_policy: Option<HostKeyPolicy>,
+    /// Number of unacknowledged download commands the broker delivers, by
+    /// default twice the thread count
+    pub prefetch: Option<u16>,
+    pub dead_letter: Option<DeadLetter>,
+    #[serde(default)]
+    pub retry: DownloadRetry,
+    /// Minimum remote file size in bytes for which an interrupted download is
+    /// resumed from the partial file, instead of started over
+    #[serde(default = "default_resume_min_size")]
+    pub resume_min_size: u64,
+    /// Download large files in parallel ranges, disabled when not specified
+    pub parallel: Option<ParallelDownload>,
+    /// Combined download limit of all threads of the source, unlimited when
+    /// not specified
+    pub max_bytes_per_second: Option<u64>,
+    /// Limits for times of the day that override `max_bytes_per_second`
+    #[serde(default)]
+    pub bandwidth_schedule: Vec<BandwidthWindow>,
+    /// Postpone the post-download action of the command until the file has
+    /// been dispatched to all targets, performed right after the download
+    /// when not specified
+    pub deferred_actions: Option<DeferredActions>,
+    /// Re-send the file of a redelivered command that was already downloaded
+    /// to the targets that did not receive it, instead of only acknowledging
+    /// the command
+    #[serde(default = "default_false")]
+    pub redispatch_redelivered: bool,
+    /// Verify downloaded files against the checksum in a sidecar file
+    pub checksum: Option<ChecksumVerification>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Md5 => "md5",
+            ChecksumAlgorithm::Sha1 => "sha1",
+            ChecksumAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+/// What happens with a downloaded file that does not match its checksum
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChecksumMismatchAction {
+    /// Discard the file and fail the attempt with a 'checksum_mismatch'
+    /// error, which is retried by default
+    #[default]
+    Retry,
+    /// Move the file to '<directory>/<source>/<download id>/<file name>',
+    /// record it and fail the attempt with a 'quarantined' error, which is
+    /// dropped by default. An existing quarantined file is never replaced.
+    Quarantine { directory: PathBuf },
+}
+
+/// Verification of downloaded files against a sidecar file in the same remote
+/// directory, e.g. 'foo.xml.md5' for 'foo.xml', containing the hex digest,
+/// optionally followed by the file name as written by md5sum and the like.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChecksumVerification {
+    pub algorithm: ChecksumAlgorithm,
+    /// Suffix of the sidecar file, '.md5', '.sha1' or '.sha256' by default
+    pub suffix: Option<String>,
+    #[serde(default)]
+    pub on_mismatch: ChecksumMismatchAction,
+}
+
+impl ChecksumVerification {
+    pub fn sidecar_path(&self, remote_path: &str) -> String {
+        match &self.suffix {
+            Some(suffix) => format!("{}{}", remote_path, suffix),
+            None => format!("{}.{}", remote_path, self.algorithm.as_str()),
+        }
+    }
+}
+
+/// Deferral of post-download actions on remote files, such as removal, until
+/// the file has been dispatched to the targets of all connections of the
+/// source that match it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeferredActions {
+    /// Interval in milliseconds at which pending actions are checked
+    #[serde(default = "default_deferred_check_interval")]
+    pub check_interval: u64,
+    /// Age in milliseconds after which a pending action is reported as stale
+    #[serde(default = "default_deferred_stale_after")]
+    pub stale_after: u64,
+}
+
+fn default_deferred_check_interval() -> u64 {
+    10_000
+}
+
+fn default_deferred_stale_after() -> u64 {
+    3_600_000
+}
+
+fn default_resume_min_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// Bandwidth limit during a window of the day in local time, e.g. from
+/// '07:00:00' to '19:00:00'. A window that ends before it starts wraps around
+/// midnight.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BandwidthWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Limit during the window, unlimited when not specified
+    pub max_bytes_per_second: Option<u64>,
+}
+
+impl BandwidthWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Parallel download of large files, where each stream downloads a range of
+/// the file over its own SFTP connection
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ParallelDownload {
+    /// Minimum file size in bytes for a parallel download
+    #[serde(default = "default_parallel_min_size")]
+    pub min_size: u64,
+    /// Number of concurrent streams per file, each with its own connection,
+    /// at most MAX_PARALLEL_STREAMS
+    #[serde(default = "default_parallel_streams")]
+    pub streams: u16,
+}
+
+/// Upper bound of the number of streams of a parallel download, to protect
+/// SFTP servers from a flood of connections
+pub const MAX_PARALLEL_STREAMS: u16 = 32;
+
+fn default_parallel_min_size() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_parallel_streams() -> u16 {
+    4
+}
+
+impl SftpSource {
+    pub fn prefetch_count(&self) -> u16 {
+        prefetch_count(self.prefetch, self.thread_count)
+    }
+}
+
+/// Prefetch derived from the number of download threads, so that every thread
+/// has a command waiting when it finishes a download
+fn prefetch_count(prefetch: Option<u16>, thread_count: usize) -> u16 {
+    prefetch.unwrap_or_else(|| std::cmp::min(thread_count.saturating_mul(2), u16::MAX as usize) as u16).max(1)
+}
+
+/// Dead-letter handling of download commands that failed. Failed commands are
+/// published to the exchange with the routing key 'source.<name>.dead-letter',
+/// and with the error and attempt count in the `x-cortex-error` and
+/// `x-cortex-attempts` headers.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetter {
+    #[serde(default = "default_dead_letter_exchange")]
+    pub exchange: String,
+    /// Queue that is declared and bound for the failed commands of the source,
+    /// by default 'source.<name>.dead-letter'
+    pub queue: Option<String>,
+}
+
+fn default_dead_letter_exchange() -> String {
+    "cortex.dead-letter".to_string()
+}
+
+impl DeadLetter {
+    pub fn queue_name(&self, source_name: &str) -> String {
+        match &self.queue {
+            Some(q) => q.clone(),
+            None => format!("source.{}.dead-letter", source_name)
+        }
+    }
+}
+
+/// Kinds of errors a download can fail with, used to decide what to do with
+/// the download command
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadErrorKind {
+    /// The SFTP connection broke during the download
+    Disconnected,
+    /// The remote file does not exist (anymore)
+    NoSuchFile,
+    /// The download could not be recorded in the database
+    Persistence,
+    /// The downloaded size differs from the remote size, usually because the
+    /// remote file is still being written
+    SizeMismatch,
+    /// The downloaded file does not match the checksum in its sidecar file
+    ChecksumMismatch,
+    /// The downloaded file does not match its checksum and was quarantined
+    Quarantined,
+    /// Any other error
+    Other,
+}
+
+impl DownloadErrorKind {
+    /// Errors of the connection to the SFTP server or the database, rather
+    /// than of the file itself
+    pub fn is_connection_error(self) -> bool {
+        matches!(self, DownloadErrorKind::Disconnected | DownloadErrorKind::Persistence)
+    }
+}
+
+/// What to do with a download command that failed
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryAction {
+    /// Try again after a backoff delay, until the maximum number of attempts
+    Retry,
+    /// Reject the command, dead-lettering it when configured
+    Fail,
+    /// Acknowledge the command without downloading
+    Drop,
+}
+
+/// Retry policy for download commands of an SFTP source that failed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownloadRetry {
+    /// Delay in milliseconds before the first retry
+    #[serde(default = "default_download_retry_initial_delay")]
+    pub initial_delay: u64,
+    /// Upper bound in milliseconds of the exponentially growing delay
+    #[serde(default = "default_download_retry_max_delay")]
+    pub max_delay: u64,
+    /// Number of failed attempts after which a command fails, or is requeued
+    /// when the last attempt failed on a connection error
+    #[serde(default = "default_download_retry_max_attempts")]
+    pub max_attempts: i32,
+    /// Action per error kind, overriding the defaults: retry on disconnects,
+    /// size and checksum mismatches and persistence errors, drop when the
+    /// file is gone or quarantined, fail otherwise
+    #[serde(default)]
+    pub on_error: HashMap<DownloadErrorKind, RetryAction>,
+}
+
+fn default_download_retry_initial_delay() -> u64 {
+    1_000
+}
+
+fn default_download_retry_max_delay() -> u64 {
+    60_000
+}
+
+fn default_download_retry_max_attempts() -> i32 {
+    5
+}
+
+impl Default for DownloadRetry {
+    fn default() -> Self {
+        DownloadRetry {
+            initial_delay: default_download_retry_initial_delay(),
+            max_delay: default_download_retry_max_delay(),
+            max_attempts: default_download_retry_max_attempts(),
+            on_error: HashMap::new(),
+        }
+    }
+}
+
+impl DownloadRetry {
+    pub fn action(&self, kind: DownloadErrorKind) -> RetryAction {
+        match self.on_error.get(&kind) {
+            Some(action) => *action,
+            None => match kind {
+                DownloadErrorKind::Disconnected => RetryAction::Retry,
+                DownloadErrorKind::NoSuchFile => RetryAction::Drop,
+                DownloadErrorKind::Persistence => RetryAction::Retry,
+                DownloadErrorKind::SizeMismatch => RetryAction::Retry,
+                DownloadErrorKind::ChecksumMismatch => RetryAction::Retry,
+                DownloadErrorKind::Quarantined => RetryAction::Drop,
+                DownloadErrorKind::Other => RetryAction::Fail,
+            }
+        }
+    }
+
+    /// Delay before the next attempt after `attempts` failed attempts
+    pub fn delay(&self, attempts: i32) -> u64 {
+        let exponent = attempts.saturating_sub(1).clamp(0, 32) as u32;
+
+        self.initial_delay.saturating_mul(2u64.saturating_pow(exponent)).min(self.max_delay)
+    }
 }
 
 /// Default Sftp downloader thread count
@@ -152,6 +551,31 @@ fn default_thread_count() -> usize {
     1
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpSource {
+    pub name: String,
+    #[serde(default = "default_thread_count")]
+    pub thread_count: usize,
+    /// Timeout in milliseconds for a complete download
+    #[serde(default = "default_http_timeout")]
+    pub timeout: u64,
+    /// Number of unacknowledged download commands the broker delivers, by
+    /// default twice the thread count
+    pub prefetch: Option<u16>,
+    pub dead_letter: Option<DeadLetter>,
+}
+
+impl HttpSource {
+    pub fn prefetch_count(&self) -> u16 {
+        prefetch_count(self.prefetch, self.thread_count)
+    }
+}
+
+/// Default HTTP download timeout
+fn default_http_timeout() -> u64 {
+    300_000
+}
+
 fn default_false() -> bool {
     false
 }
@@ -164,6 +588,21 @@ pub struct Storage {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CommandQueue {
     pub address: String,
+    /// Delay in milliseconds before the first reconnect after losing the
+    /// connection, doubled for every failed attempt
+    #[serde(default = "default_reconnect_initial_delay")]
+    pub reconnect_initial_delay: u64,
+    /// Upper bound in milliseconds of the reconnect delay
+    #[serde(default = "default_reconnect_max_delay")]
+    pub reconnect_max_delay: u64,
+}
+
+fn default_reconnect_initial_delay() -> u64 {
+    1_000
+}
+
+fn default_reconnect_max_delay() -> u64 {
+    60_000
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -176,6 +615,83 @@ pub struct HttpServer {
     pub address: std::net::SocketAddr,
 }
 
+/// Retry policy for deliveries of files to targets that failed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeliveryRetry {
+    /// Delay in milliseconds before the first retry
+    #[serde(default = "default_retry_initial_delay")]
+    pub initial_delay: u64,
+    /// Upper bound in milliseconds of the exponentially growing delay
+    #[serde(default = "default_retry_max_delay")]
+    pub max_delay: u64,
+    /// Number of failed attempts after which a delivery is marked dead
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: i32,
+    /// Interval in milliseconds at which due retries are picked up
+    #[serde(default = "default_retry_interval")]
+    pub interval: u64,
+}
+
+fn default_retry_initial_delay() -> u64 {
+    10_000
+}
+
+fn default_retry_max_delay() -> u64 {
+    3_600_000
+}
+
+fn default_retry_max_attempts() -> i32 {
+    10
+}
+
+fn default_retry_interval() -> u64 {
+    5_000
+}
+
+impl Default for DeliveryRetry {
+    fn default() -> Self {
+        DeliveryRetry {
+            initial_delay: default_retry_initial_delay(),
+            max_delay: default_retry_max_delay(),
+            max_attempts: default_retry_max_attempts(),
+            interval: default_retry_interval(),
+        }
+    }
+}
+
+/// Re-injection of files that were stored but never dispatched to a target,
+/// e.g. because the process stopped while events were queued.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Backfill {
+    /// Run a backfill for all connections at startup
+    #[serde(default = "default_true")]
+    pub on_startup: bool,
+    /// Optional interval in milliseconds at which the backfill is repeated
+    pub interval: Option<u64>,
+    /// Only files stored at least this many milliseconds ago are considered,
+    /// so that files that are still in flight are not sent twice
+    #[serde(default = "default_backfill_grace_interval")]
+    pub grace_interval: u64,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_backfill_grace_interval() -> u64 {
+    300_000
+}
+
+impl Default for Backfill {
+    fn default() -> Self {
+        Backfill {
+            on_startup: true,
+            interval: None,
+            grace_interval: default_backfill_grace_interval(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Settings {
     pub storage: Storage,
@@ -184,12 +700,51 @@ pub struct Settings {
     pub directory_sources: Vec<DirectorySource>,
     #[serde(default = "default_directory_targets")]
     pub directory_targets: Vec<DirectoryTarget>,
+    #[serde(default = "default_sftp_targets")]
+    pub sftp_targets: Vec<SftpTarget>,
+    #[serde(default = "default_http_targets")]
+    pub http_targets: Vec<HttpTarget>,
     pub sftp_sources: Vec<SftpSource>,
+    #[serde(default = "default_http_sources")]
+    pub http_sources: Vec<HttpSource>,
     pub connections: Vec<Connection>,
     pub postgresql: Postgresql,
     pub http_server: HttpServer,
     #[serde(default = "default_scan_interval")]
-    pub scan_interval: u64
+    pub scan_interval: u64,
+    #[serde(default)]
+    pub delivery_retry: DeliveryRetry,
+    #[serde(default)]
+    pub backfill: Backfill,
+    /// Time in milliseconds that in-flight work may take to finish on shutdown
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+impl Settings {
+    /// Check the combinations of settings that deserialization cannot reject
+    pub fn validate(&self) -> Result<(), String> {
+        for sftp_source in &self.sftp_sources {
+            HostKeyPolicy::validate(sftp_source.host_key_policy, &sftp_source.known_hosts, &sftp_source.host_key_fingerprints)
+                .map_err(|e| format!("SFTP source '{}': {}", &sftp_source.name, e))?;
+
+            if let Some(parallel) = &sftp_source.parallel {
+                if parallel.streams == 0 || parallel.streams > MAX_PARALLEL_STREAMS {
+                    return Err(format!(
+                        "SFTP source '{}': parallel streams must be between 1 and {}, not {}",
+                        &sftp_source.name, MAX_PARALLEL_STREAMS, parallel.streams
+                    ));
+                }
+            }
+        }
+
+        for sftp_target in &self.sftp_targets {
+            HostKeyPolicy::validate(sftp_target.host_key_policy, &sftp_target.known_hosts, &sftp_target.host_key_fingerprints)
+                .map_err(|e| format!("SFTP target '{}': {}", &sftp_target.name, e))?;
+        }
+
+        Ok(())
+    }
 }
 
 /// Default directory scan (sweep) interval
@@ -197,6 +752,11 @@ fn default_scan_interval() -> u64 {
     60_000
 }
 
+/// Default shutdown drain timeout
+fn default_drain_timeout() -> u64 {
+    30_000
+}
+
 fn default_directory_sources() -> Vec<DirectorySource> {
     vec![]
 }
@@ -205,6 +765,18 @@ fn default_directory_targets() -> Vec<DirectoryTarget> {
     vec![]
 }
 
+fn default_sftp_targets() -> Vec<SftpTarget> {
+    vec![]
+}
+
+fn default_http_targets() -> Vec<HttpTarget> {
+    vec![]
+}
+
+fn default_http_sources() -> Vec<HttpSource> {
+    vec![]
+}
+
 impl Default for Settings {
     fn default() -> Self {
         Settings {
@@ -212,7 +784,9 @@ impl Default for Settings {
                 directory: PathBuf::from("/cortex/storage"),
             },
             command_queue: CommandQueue {
-                address: "127.0.0.1:5672".parse().unwrap()
+                address: "127.0.0.1:5672".parse().unwrap(),
+                reconnect_initial_delay: 1_000,
+                reconnect_max_delay: 60_000,
             },
             directory_sources: vec![DirectorySource {
                 name: "mixed-directory".to_string(),
@@ -231,9 +805,51 @@ impl Default for Settings {
                     address: "127.0.0.1:5672".parse().unwrap(),
                     exchange: "".to_string(),
                     routing_key: "red-consumer".to_string(),
+                    content_type: "application/json".to_string(),
+                    headers: [
+                        ("file_id".to_string(), "{{ file_id }}".to_string()),
+                        ("source".to_string(), "{{ source_name }}".to_string()),
+                    ].iter().cloned().collect(),
+                    retries: 3,
+                    retry_delay: 1_000,
                 })),
                 permissions: 100
             }],
+            sftp_targets: vec![SftpTarget {
+                name: "yellow".to_string(),
+                address: "127.0.0.1:22".parse().unwrap(),
+                username: "cortex".to_string(),
+                password: Some("password".to_string()),
+                key_file: None,
+                compress: false,
+                known_hosts: Some(PathBuf::from("/etc/cortex/known_hosts")),
+                host_key_fingerprints: vec![],
+                host_key_policy: Some(HostKeyPolicy::AcceptNew),
+                directory: PathBuf::from("/upload/yellow-consumer"),
+                permissions: Some(0o644),
+                overwrite: true,
+                thread_count: 2,
+                notify: None,
+                max_bytes_per_second: None,
+                bandwidth_schedule: vec![],
+            }],
+            http_targets: vec![HttpTarget {
+                name: "purple".to_string(),
+                url: "http://127.0.0.1:8080/upload/{{ file_name }}".to_string(),
+                method: HttpUploadMethod::Put,
+                headers: HashMap::new(),
+                multipart_field: "file".to_string(),
+                timeout: 300_000,
+                thread_count: 2,
+                notify: Some(Notify::Webhook(WebhookNotify {
+                    url: "http://127.0.0.1:8080/notify".to_string(),
+                    message_template: "{\"file_path\": \"{{ file_path }}\"}".to_string(),
+                    headers: HashMap::new(),
+                    timeout: 10_000,
+                    retries: 3,
+                    retry_delay: 1_000,
+                })),
+            }],
             sftp_sources: vec![
                 SftpSource {
                     name: "red".to_string(),
@@ -243,6 +859,22 @@ impl Default for Settings {
                     key_file: None,
                     compress: false,
                     thread_count: 4,
+                    known_hosts: Some(PathBuf::from("/etc/cortex/known_hosts")),
+                    host_key_fingerprints: vec![],
+                    host_key_policy: Some(HostKeyPolicy::AcceptNew),
+                    prefetch: None,
+                    dead_letter: Some(DeadLetter {
+                        exchange: "cortex.dead-letter".to_string(),
+                        queue: None,
+                    }),
+                    retry: DownloadRetry::default(),
+                    resume_min_size: default_resume_min_size(),
+                    parallel: None,
+                    max_bytes_per_second: None,
+                    bandwidth_schedule: vec![],
+                    deferred_actions: None,
+                    redispatch_redelivered: false,
+                    checksum: None,
                 },
                 SftpSource {
                     name: "blue".to_string(),
@@ -252,6 +884,34 @@ impl Default for Settings {
                     key_file: None,
                     compress: false,
                     thread_count: 4,
+                    known_hosts: Some(PathBuf::from("/etc/cortex/known_hosts")),
+                    host_key_fingerprints: vec![],
+                    host_key_policy: Some(HostKeyPolicy::AcceptNew),
+                    prefetch: None,
+                    dead_letter: Some(DeadLetter {
+                        exchange: "cortex.dead-letter".to_string(),
+                        queue: None,
+                    }),
+                    retry: DownloadRetry::default(),
+                    resume_min_size: default_resume_min_size(),
+                    parallel: None,
+                    max_bytes_per_second: None,
+                    bandwidth_schedule: vec![],
+                    deferred_actions: None,
+                    redispatch_redelivered: false,
+                    checksum: None,
+                },
+            ],
+            http_sources: vec![
+                HttpSource {
+                    name: "green".to_string(),
+                    thread_count: 2,
+                    timeout: 300_000,
+                    prefetch: None,
+                    dead_letter: Some(DeadLetter {
+                        exchange: "cortex.dead-letter".to_string(),
+                        queue: None,
+                    }),
                 },
             ],
             connections: vec![],
@@ -261,7 +921,10 @@ impl Default for Settings {
             http_server: HttpServer {
                 address: "0.0.0.0:56008".parse().unwrap(),
             },
-            scan_interval: 60_000
+            scan_interval: 60_000,
+            delivery_retry: DeliveryRetry::default(),
+            backfill: Backfill::default(),
+            drain_timeout: 30_000,
         }
     }
 }
@@ -1,22 +1,30 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::{fmt, fmt::Display, time};
 
 extern crate lapin;
 
 use futures::StreamExt;
 
-use lapin::options::{BasicConsumeOptions, QueueBindOptions, QueueDeclareOptions, BasicNackOptions, BasicAckOptions};
+use lapin::options::{BasicCancelOptions, BasicConsumeOptions, BasicQosOptions, ConfirmSelectOptions, QueueBindOptions, QueueDeclareOptions, BasicNackOptions, BasicAckOptions};
 use lapin::types::FieldTable;
+use lapin::ConnectionProperties;
 
-use crossbeam_channel::{Sender, TrySendError};
+use tokio::sync::oneshot;
 
-use crate::base_types::MessageResponse;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+
+use serde::de::DeserializeOwned;
+
+use crate::base_types::{AmqpHealth, MessageResponse};
+use crate::dead_letter;
 use crate::metrics;
+use crate::settings;
 
-use cortex_core::SftpDownload;
+const CONSUMER_TAG: &str = "cortex-dispatcher";
 
 #[derive(Clone, Debug)]
 pub enum ConsumeError {
-    ChannelFull,
     ChannelDisconnected,
     DeserializeError,
 	RabbitMQError(lapin::Error)
@@ -37,46 +45,281 @@ impl Display for ConsumeError {
     }
 }
 
-async fn setup_message_responder(channel: lapin::Channel, mut ack_receiver: tokio::sync::mpsc::Receiver<MessageResponse>) -> Result<(), ConsumeError> {
+/// Number of bits of a delivery id that hold the AMQP delivery tag. The bits
+/// above hold the generation of the connection the message was received on,
+/// because delivery tags are only valid on the channel that delivered them.
+const GENERATION_SHIFT: u32 = 48;
+const DELIVERY_TAG_MASK: u64 = (1 << GENERATION_SHIFT) - 1;
+const GENERATION_MASK: u64 = (1 << (64 - GENERATION_SHIFT)) - 1;
+
+fn delivery_id(generation: u64, delivery_tag: u64) -> u64 {
+	(generation << GENERATION_SHIFT) | (delivery_tag & DELIVERY_TAG_MASK)
+}
+
+/// Settings of the command queue of a source
+#[derive(Debug, Clone)]
+pub struct SourceQueue {
+	pub source_name: String,
+	pub prefetch: u16,
+	pub dead_letter: Option<settings::DeadLetter>,
+}
+
+/// Payload and headers of the unacknowledged commands of a connection, by
+/// delivery id, so that failed commands can be dead-lettered
+type PendingMessages = Arc<Mutex<HashMap<u64, (Vec<u8>, FieldTable)>>>;
+
+/// Send a response of a downloader to the broker, unless the message was
+/// received on a previous connection, in which case the broker has already
+/// put it back on the queue.
+async fn send_response(channel: &lapin::Channel, generation: u64, source_queue: &SourceQueue, pending: &PendingMessages, message_response: MessageResponse) -> Result<(), ConsumeError> {
+	let id = match message_response {
+		MessageResponse::Ack { delivery_tag } => delivery_tag,
+		MessageResponse::Nack { delivery_tag, .. } => delivery_tag,
+		MessageResponse::Requeue { delivery_tag } => delivery_tag,
+	};
+
+	if id >> GENERATION_SHIFT != generation {
+		debug!("Dropping response for message of a previous connection");
+		return Ok(())
+	}
+
+	let delivery_tag = id & DELIVERY_TAG_MASK;
+
+	let pending_message = pending.lock().unwrap().remove(&id);
+
+	match message_response {
+		MessageResponse::Ack { .. } => {
+			channel.basic_ack(delivery_tag, BasicAckOptions { multiple: false }).await?;
+			debug!("Sent Ack for {}", delivery_tag);
+		},
+		MessageResponse::Nack { error, attempts, .. } => {
+			match (&source_queue.dead_letter, pending_message) {
+				(Some(dead_letter), Some((data, headers))) => {
+					let publish_result = dead_letter::publish(channel, dead_letter, &source_queue.source_name, data, headers, &error, attempts).await;
+
+					match publish_result {
+						Ok(_) => {
+							channel.basic_ack(delivery_tag, BasicAckOptions { multiple: false }).await?;
+							debug!("Sent Ack for dead-lettered {}", delivery_tag);
+						},
+						Err(e) => {
+							// Keep the command instead of losing it
+							error!("Error dead-lettering command of source '{}': {}", &source_queue.source_name, e);
+							channel.basic_nack(delivery_tag, BasicNackOptions { multiple: false, requeue: true }).await?;
+						}
+					}
+				},
+				_ => {
+					channel.basic_nack(delivery_tag, BasicNackOptions { multiple: false, requeue: false }).await?;
+					debug!("Sent Nack for {}", delivery_tag);
+				}
+			}
+		},
+		MessageResponse::Requeue { .. } => {
+			channel.basic_nack(delivery_tag, BasicNackOptions { multiple: false, requeue: true }).await?;
+			debug!("Sent Nack with requeue for {}", delivery_tag);
+		}
+	}
+
+	Ok(())
+}
+
+/// Send the acks and nacks of the downloaders to the broker. Ends when all
+/// senders of the response channel are dropped, i.e. when all downloader
+/// threads of the source have stopped.
+async fn respond(channel: &lapin::Channel, generation: u64, source_queue: &SourceQueue, pending: &PendingMessages, ack_receiver: &mut tokio::sync::mpsc::Receiver<MessageResponse>) -> Result<(), ConsumeError> {
 	while let Some(message_response) = ack_receiver.recv().await {
-		match message_response {
-			MessageResponse::Ack { delivery_tag } => {
-				channel.basic_ack(delivery_tag, BasicAckOptions { multiple: false }).await?;
-				debug!("Sent Ack for {}", delivery_tag);
+		send_response(channel, generation, source_queue, pending, message_response).await?;
+	}
+
+	Ok(())
+}
+
+/// How a connection of a supervised consumer ended
+enum ConsumerExit {
+	Stopped,
+	DownloadersStopped,
+	ConnectionLost(ConsumeError),
+}
+
+/// Consume the commands of a source and send the responses of its downloaders
+/// to the broker, reconnecting with exponential backoff when the connection to
+/// the broker is lost. Commands that were received on a lost connection and
+/// were not picked up yet are discarded, because the broker redelivers them.
+///
+/// After the stop signal, no new commands are consumed and the responses are
+/// sent until all downloaders of the source have stopped.
+pub async fn supervise<T>(
+	command_queue: settings::CommandQueue,
+	source_queue: SourceQueue,
+	command_sender: Sender<(u64, T)>,
+	command_receiver: Receiver<(u64, T)>,
+	mut ack_receiver: tokio::sync::mpsc::Receiver<MessageResponse>,
+	mut stop_receiver: oneshot::Receiver<()>,
+	health: AmqpHealth,
+) -> Result<(), ConsumeError>
+where
+	T: DeserializeOwned,
+{
+	let source_name = source_queue.source_name.clone();
+	let mut generation: u64 = 0;
+	let mut delay = command_queue.reconnect_initial_delay;
+
+	health.set(&source_name, false);
+
+	loop {
+		debug!("Connecting to AMQP service at {} for source '{}'", &command_queue.address, &source_name);
+
+		let connect_result = connect(&command_queue.address).await;
+
+		let (connection, channel) = match connect_result {
+			Ok(c) => c,
+			Err(e) => {
+				warn!("Could not connect to AMQP service for source '{}', retrying in {} ms: {}", &source_name, delay, e);
+
+				let stopped = tokio::select!(
+					_ = tokio::time::sleep(time::Duration::from_millis(delay)) => false,
+					_ = &mut stop_receiver => true
+				);
+
+				if stopped {
+					// Without a connection the responses can not be sent, the
+					// messages are redelivered by the broker.
+					while ack_receiver.recv().await.is_some() {}
+
+					return Ok(())
+				}
+
+				delay = std::cmp::min(delay * 2, command_queue.reconnect_max_delay);
+
+				continue;
+			}
+		};
+
+		generation = (generation + 1) & GENERATION_MASK;
+		delay = command_queue.reconnect_initial_delay;
+
+		let discarded = command_receiver.try_iter().count();
+
+		if discarded > 0 {
+			info!("Discarded {} commands of source '{}' from a previous connection", discarded, &source_name);
+		}
+
+		let pending: PendingMessages = Arc::new(Mutex::new(HashMap::new()));
+
+		health.set(&source_name, true);
+
+		info!("Connected to AMQP service for source '{}'", &source_name);
+
+		let exit = {
+			let consume_future = start(channel.clone(), generation, &source_queue, &pending, command_sender.clone());
+			let respond_future = respond(&channel, generation, &source_queue, &pending, &mut ack_receiver);
+
+			tokio::select!(
+				r = consume_future => ConsumerExit::ConnectionLost(r.err().unwrap_or(ConsumeError::ChannelDisconnected)),
+				r = respond_future => match r {
+					Ok(_) => ConsumerExit::DownloadersStopped,
+					Err(e) => ConsumerExit::ConnectionLost(e),
+				},
+				_ = &mut stop_receiver => ConsumerExit::Stopped
+			)
+		};
+
+		match exit {
+			ConsumerExit::Stopped => {
+				debug!("Interrupted command consumer stream '{}'", &source_name);
+
+				if let Err(e) = channel.basic_cancel(CONSUMER_TAG, BasicCancelOptions::default()).await {
+					warn!("Error cancelling consumer of source '{}': {}", &source_name, e);
+				}
+
+				if let Err(e) = respond(&channel, generation, &source_queue, &pending, &mut ack_receiver).await {
+					error!("Error sending responses of source '{}': {}", &source_name, e);
+
+					while ack_receiver.recv().await.is_some() {}
+				}
+
+				if let Err(e) = connection.close(200, "Shutdown").await {
+					error!("Error closing AMQP connection: {}", e);
+				}
+
+				return Ok(())
+			},
+			ConsumerExit::DownloadersStopped => {
+				if let Err(e) = connection.close(200, "Shutdown").await {
+					error!("Error closing AMQP connection: {}", e);
+				}
+
+				return Ok(())
 			},
-			MessageResponse::Nack { delivery_tag } => {
-				channel.basic_nack(delivery_tag, BasicNackOptions { multiple: false, requeue: false }).await?;
-				debug!("Sent Nack for {}", delivery_tag);
+			ConsumerExit::ConnectionLost(e) => {
+				health.set(&source_name, false);
+
+				metrics::AMQP_RECONNECTS_COUNTER
+					.with_label_values(&[&source_name])
+					.inc();
+
+				warn!("AMQP connection of source '{}' lost, reconnecting: {}", &source_name, e);
 			}
 		}
 	}
+}
 
-	Ok(())
+async fn connect(address: &str) -> Result<(lapin::Connection, lapin::Channel), lapin::Error> {
+	let connection = lapin::Connection::connect(address, ConnectionProperties::default()).await?;
+
+	let channel = connection.create_channel().await?;
+
+	Ok((connection, channel))
+}
+
+/// Send a command to the downloader threads. The channel has room for the
+/// prefetch count, so it is only full when the commands of a lost connection
+/// are still in it. The command then waits for room instead of being
+/// requeued.
+async fn send_command<T>(command_sender: &Sender<(u64, T)>, command: (u64, T)) -> Result<(), ConsumeError> {
+	let mut command = command;
+
+	loop {
+		match command_sender.try_send(command) {
+			Ok(_) => return Ok(()),
+			Err(TrySendError::Disconnected(_)) => return Err(ConsumeError::ChannelDisconnected),
+			Err(TrySendError::Full(c)) => {
+				command = c;
+				tokio::time::sleep(time::Duration::from_millis(100)).await;
+			}
+		}
+	}
 }
 
-pub async fn start(
+/// Consume download commands from the queue of a source and send them to the
+/// downloader threads of the source. The command type depends on the kind of
+/// source, e.g. `SftpDownload` or `HttpDownload`.
+async fn start<T>(
     amqp_channel: lapin::Channel,
-    sftp_source_name: String,
-	ack_receiver: tokio::sync::mpsc::Receiver<MessageResponse>,
-    command_sender: Sender<(u64, SftpDownload)>
-) -> Result<(), ConsumeError> {
-	let sftp_source_name_2 = sftp_source_name.clone();
+    generation: u64,
+    source_queue: &SourceQueue,
+    pending: &PendingMessages,
+    command_sender: Sender<(u64, T)>
+) -> Result<(), ConsumeError>
+where
+	T: DeserializeOwned,
+{
+	let source_name = &source_queue.source_name;
+	let prefetch = source_queue.prefetch;
 	
-	debug!("Creating SFTP command AMQP channel '{}'", &sftp_source_name);
+	debug!("Creating command AMQP channel '{}'", &source_name);
 
 	let id = amqp_channel.id();
-	info!("Created SFTP command AMQP channel with id {}", id);
-
-	tokio::spawn(setup_message_responder(amqp_channel.clone(), ack_receiver));
+	info!("Created command AMQP channel with id {}", id);
 
-	let consumer_tag = "cortex-dispatcher";
-	let queue_name = format!("source.{}", &sftp_source_name);
+	let queue_name = format!("source.{}", &source_name);
 
 	let _queue = amqp_channel
 		.queue_declare(&queue_name, QueueDeclareOptions::default(), FieldTable::default()).await?;
 
 	info!("channel {} declared queue '{}'", id, &queue_name);
-	let routing_key = format!("source.{}", &sftp_source_name);
+	let routing_key = format!("source.{}", &source_name);
 	let exchange = "amq.direct";
 
 	amqp_channel.queue_bind(
@@ -89,9 +332,22 @@ pub async fn start(
 
 	debug!("Queue '{}' bound to exchange '{}' for routing key '{}'", &queue_name, &exchange, &routing_key);
 
+	if let Some(dead_letter) = &source_queue.dead_letter {
+		dead_letter::declare(&amqp_channel, dead_letter, source_name).await?;
+
+		// Failed commands are only acked once their dead-letter copy is confirmed
+		amqp_channel.confirm_select(ConfirmSelectOptions::default()).await?;
+	}
+
+	// Limit the number of unacknowledged commands, so that the broker holds
+	// the backlog instead of pushing it all to us.
+	amqp_channel.basic_qos(prefetch, BasicQosOptions::default()).await?;
+
+	debug!("Prefetch of channel {} set to {}", id, prefetch);
+
 	// Setup command consuming stream
 	let mut consumer = amqp_channel.basic_consume(
-		&queue_name, &consumer_tag, BasicConsumeOptions::default(), FieldTable::default()
+		&queue_name, CONSUMER_TAG, BasicConsumeOptions::default(), FieldTable::default()
 	).await?;
 
 	while let Some(message) = consumer.next().await {
@@ -106,42 +362,51 @@ pub async fn start(
 
 		debug!("Received message from AMQP queue '{}'", &queue_name);
 		metrics::MESSAGES_RECEIVED_COUNTER
-			.with_label_values(&[&sftp_source_name_2])
+			.with_label_values(&[source_name])
 			.inc();
 
-		let deserialize_result: serde_json::Result<SftpDownload> = serde_json::from_slice(delivery.data.as_slice());
+		let deserialize_result: serde_json::Result<T> = serde_json::from_slice(delivery.data.as_slice());
 
 		let result = match deserialize_result {
-			Ok(sftp_download) => {
-				let send_result = action_command_sender.try_send((delivery.delivery_tag, sftp_download));
-				
-				match send_result {
-					Ok(_) => Ok(()),
-					Err(e) => {
-						match e {
-							TrySendError::Disconnected(_) => Err(ConsumeError::ChannelDisconnected),
-							TrySendError::Full(_) => Err(ConsumeError::ChannelFull)
+			Ok(command) => {
+				let id = delivery_id(generation, delivery.delivery_tag);
+
+				if source_queue.dead_letter.is_some() {
+					let headers = delivery.properties.headers().clone().unwrap_or_default();
+
+					pending.lock().unwrap().insert(id, (delivery.data.clone(), headers));
+				}
+
+				send_command(&action_command_sender, (id, command)).await
+			},
+			Err(e) => {
+				let error = format!("Error deserializing message: {}", e);
+				error!("{}", &error);
+
+				if let Some(dead_letter) = &source_queue.dead_letter {
+					let headers = delivery.properties.headers().clone().unwrap_or_default();
+
+					match dead_letter::publish(&channel, dead_letter, source_name, delivery.data.clone(), headers, &error, None).await {
+						Ok(_) => {
+							channel.basic_ack(delivery.delivery_tag, BasicAckOptions { multiple: false }).await?;
+						},
+						Err(e) => {
+							// Keep the command instead of losing it
+							error!("Error dead-lettering command of source '{}': {}", source_name, e);
+							channel.basic_nack(delivery.delivery_tag, BasicNackOptions { multiple: false, requeue: true }).await?;
 						}
 					}
+
+					continue;
 				}
-			},
-			Err(_e) => {
+
 				Err(ConsumeError::DeserializeError)
 			}
 		};
 
 		if let Err(e) = result {
 			let requeue_message = match e {
-				ConsumeError::DeserializeError => {
-					error!("Error deserializing message: {}", e);
-					false
-				}
-				ConsumeError::ChannelFull => {
-					debug!("Could not send command on channel: channel full");
-					tokio::time::sleep(time::Duration::from_millis(200)).await;
-					// Put the message back on the queue, because we could temporarily not process it
-					true
-				},
+				ConsumeError::DeserializeError => false,
 				ConsumeError::ChannelDisconnected => {
 					error!("Channel disconnected");
 					true
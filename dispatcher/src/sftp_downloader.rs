@@ -1,8 +1,8 @@
 use std::convert::TryFrom;
-use std::fs::File;
-use std::io;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::cell::RefCell;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{thread, time};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -11,20 +11,23 @@ extern crate failure;
 
 use crossbeam_channel::{Receiver, RecvTimeoutError};
 
-use retry::{retry, OperationResult, delay::Fixed};
-
 use crate::event::FileEvent;
 use crate::metrics;
-use crate::persistence::Persistence;
+use crate::persistence::{Persistence, SftpDownloadState};
+use crate::post_download;
 use crate::settings;
 use crate::base_types::MessageResponse;
 use crate::local_storage::LocalStorage;
+use crate::throttle::{Throttle, ThrottledReader};
 
-use cortex_core::sftp_connection::{SftpConfig, SftpConnection};
-use cortex_core::SftpDownload;
+use cortex_core::sftp_connection::{HostKeyPolicy, SftpConfig, SftpConnection};
+use cortex_core::{Marker, MarkerAction, PostDownloadAction, SftpDownload};
 
+use md5::Md5;
+use sha1::Sha1;
 use sha2::{Digest, Sha256};
-use tee::TeeReader;
+
+use cortex_core::ssh2::{self, FileStat};
 
 use chrono::{Utc, DateTime, NaiveDateTime};
 
@@ -34,9 +37,360 @@ error_chain! {
         NoSuchFileError
         ConnectInterrupted
         PersistenceError
+        SizeMismatchError(expected: u64, actual: u64) {
+            description("downloaded size differs from remote size")
+            display("downloaded {} bytes where {} were expected", actual, expected)
+        }
+        ChecksumMismatchError(expected: String, actual: String) {
+            description("downloaded file does not match its checksum")
+            display("checksum {} does not match expected {}", actual, expected)
+        }
+        QuarantinedError(path: String) {
+            description("downloaded file does not match its checksum and was quarantined")
+            display("checksum mismatch, quarantined as '{}'", path)
+        }
+    }
+}
+
+/// Size of the buffer used to copy remote files
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Maximum size of a checksum sidecar file that is read
+const SIDECAR_MAX_SIZE: u64 = 64 * 1024;
+
+/// Final outcome of a download command that did not succeed
+enum DownloadFailure {
+    /// The command is rejected with the error message and the number of
+    /// attempts recorded for the download
+    Fail(String, i32),
+    /// The command is acknowledged without a download
+    Drop(String),
+    /// The command is put back on the queue, to be tried again later
+    Requeue(String),
+}
+
+fn error_kind(e: &Error) -> settings::DownloadErrorKind {
+    match e.kind() {
+        ErrorKind::DisconnectedError => settings::DownloadErrorKind::Disconnected,
+        ErrorKind::NoSuchFileError => settings::DownloadErrorKind::NoSuchFile,
+        ErrorKind::PersistenceError => settings::DownloadErrorKind::Persistence,
+        ErrorKind::SizeMismatchError(_, _) => settings::DownloadErrorKind::SizeMismatch,
+        ErrorKind::ChecksumMismatchError(_, _) => settings::DownloadErrorKind::ChecksumMismatch,
+        ErrorKind::QuarantinedError(_) => settings::DownloadErrorKind::Quarantined,
+        _ => settings::DownloadErrorKind::Other,
+    }
+}
+
+fn error_message(e: &Error) -> String {
+    let msg_list: Vec<String> = e.iter().map(|sub_err| sub_err.to_string()).collect();
+
+    msg_list.join(": ")
+}
+
+/// Temporary path in the same directory as the final path, so that the
+/// download can be moved into place with an atomic rename.
+pub fn temp_path(local_path: &Path) -> Result<PathBuf> {
+    match local_path.file_name() {
+        Some(file_name) => Ok(local_path.with_file_name(format!(".{}.part", file_name.to_string_lossy()))),
+        None => bail!("No file name in local path '{}'", local_path.to_string_lossy())
+    }
+}
+
+/// Copy a remote file to a local file and flush it to disk, returning the
+/// total number of bytes in the local file and the SHA256 hash of its content.
+/// With a non-zero offset, the download continues after the first `offset`
+/// bytes of an existing partial file, which are hashed again first.
+fn download_to<R: Read + Seek>(remote_file: &mut R, path: &Path, offset: u64, throttle: &Throttle) -> Result<(u64, String)> {
+    let mut sha256 = Sha256::new();
+
+    let mut local_file = if offset > 0 {
+        let mut partial_file = OpenOptions::new().read(true).write(true).open(path)
+            .chain_err(|| format!("Error opening partial file '{}'", path.to_string_lossy()))?;
+
+        let bytes_hashed = io::copy(&mut (&mut partial_file).take(offset), &mut sha256)
+            .chain_err(|| format!("Error reading partial file '{}'", path.to_string_lossy()))?;
+
+        if bytes_hashed != offset {
+            bail!("Partial file '{}' is shorter than {} bytes", path.to_string_lossy(), offset);
+        }
+
+        partial_file.set_len(offset)
+            .chain_err(|| format!("Error truncating partial file '{}'", path.to_string_lossy()))?;
+
+        partial_file.seek(SeekFrom::Start(offset))
+            .chain_err(|| format!("Error seeking in partial file '{}'", path.to_string_lossy()))?;
+
+        remote_file.seek(SeekFrom::Start(offset))
+            .chain_err(|| ErrorKind::DisconnectedError)?;
+
+        partial_file
+    } else {
+        File::create(path)
+            .chain_err(|| format!("Error creating local file '{}'", path.to_string_lossy()))?
+    };
+
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+    let mut bytes_copied = offset;
+
+    loop {
+        // A failing read from the remote file means that the session broke,
+        // in which case the download can be resumed on a new connection.
+        let n = match remote_file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::with_chain(e, ErrorKind::DisconnectedError))
+        };
+
+        throttle.consume(n as u64);
+
+        sha256.update(&buffer[..n]);
+
+        local_file.write_all(&buffer[..n])
+            .chain_err(|| format!("Error writing to local file '{}'", path.to_string_lossy()))?;
+
+        bytes_copied += n as u64;
+    }
+
+    local_file.sync_all()
+        .chain_err(|| format!("Error syncing local file '{}'", path.to_string_lossy()))?;
+
+    Ok((bytes_copied, format!("{:x}", sha256.finalize())))
+}
+
+/// Download a file in ranges of equal size, each over its own SFTP connection,
+/// directly into place in the local file. The content is hashed afterwards.
+fn download_parallel(sftp_config: &SftpConfig, remote_path: &Path, size: u64, streams: u16, path: &Path, throttle: &Throttle, stop: &Arc<AtomicBool>) -> Result<(u64, String)> {
+    {
+        let local_file = File::create(path)
+            .chain_err(|| format!("Error creating local file '{}'", path.to_string_lossy()))?;
+
+        local_file.set_len(size)
+            .chain_err(|| format!("Error allocating local file '{}'", path.to_string_lossy()))?;
+    }
+
+    let range_size = size.div_ceil(u64::from(streams));
+
+    let results: Vec<Result<u64>> = thread::scope(|scope| {
+        let handles: Vec<thread::ScopedJoinHandle<Result<u64>>> = (0..u64::from(streams)).map(|i| {
+            let start = std::cmp::min(i * range_size, size);
+            let end = std::cmp::min(start + range_size, size);
+
+            scope.spawn(move || download_range(sftp_config, remote_path, path, start, end, throttle, stop))
+        }).collect();
+
+        handles.into_iter().map(|handle| {
+            handle.join().unwrap_or_else(|_| Err("Range download thread panicked".into()))
+        }).collect()
+    });
+
+    let mut bytes_copied: u64 = 0;
+
+    for result in results {
+        bytes_copied += result?;
+    }
+
+    let mut local_file = File::open(path)
+        .chain_err(|| format!("Error opening local file '{}'", path.to_string_lossy()))?;
+
+    local_file.sync_all()
+        .chain_err(|| format!("Error syncing local file '{}'", path.to_string_lossy()))?;
+
+    let mut sha256 = Sha256::new();
+
+    io::copy(&mut local_file, &mut sha256)
+        .chain_err(|| format!("Error hashing local file '{}'", path.to_string_lossy()))?;
+
+    Ok((bytes_copied, format!("{:x}", sha256.finalize())))
+}
+
+/// Download the bytes from `start` up to `end` of a remote file to the same
+/// range of the local file, using a new SFTP connection.
+fn download_range(sftp_config: &SftpConfig, remote_path: &Path, path: &Path, start: u64, end: u64, throttle: &Throttle, stop: &Arc<AtomicBool>) -> Result<u64> {
+    let sftp_connection = SftpConnection::connect_loop(sftp_config.clone(), stop.clone())
+        .chain_err(|| ErrorKind::DisconnectedError)?;
+
+    let mut remote_file = open_remote(&sftp_connection.sftp, remote_path)?;
+
+    remote_file.seek(SeekFrom::Start(start))
+        .chain_err(|| ErrorKind::DisconnectedError)?;
+
+    let mut local_file = OpenOptions::new().write(true).open(path)
+        .chain_err(|| format!("Error opening local file '{}'", path.to_string_lossy()))?;
+
+    local_file.seek(SeekFrom::Start(start))
+        .chain_err(|| format!("Error seeking in local file '{}'", path.to_string_lossy()))?;
+
+    let mut range = ThrottledReader::new(remote_file.take(end - start), throttle);
+
+    io::copy(&mut range, &mut local_file)
+        .chain_err(|| ErrorKind::DisconnectedError)
+}
+
+/// Move a downloaded file and its downloaded marker, if any, from their
+/// temporary paths in place. The marker follows the file, so that it never
+/// announces a missing file, and neither is left behind when either fails.
+fn place_download(temp_path: &Path, local_path: &Path, marker: Option<&(PathBuf, PathBuf)>) -> Result<()> {
+    let result = std::fs::rename(temp_path, local_path)
+        .chain_err(|| format!("Error renaming '{}' to '{}'", temp_path.to_string_lossy(), local_path.to_string_lossy()))
+        .and_then(|_| match marker {
+            Some((temp_marker, local_marker)) => std::fs::rename(temp_marker, local_marker)
+                .chain_err(|| format!("Error renaming '{}' to '{}'", temp_marker.to_string_lossy(), local_marker.to_string_lossy()))
+                .inspect_err(|_| remove_partial(local_path)),
+            None => Ok(())
+        });
+
+    if result.is_err() {
+        if let Some((temp_marker, _)) = marker {
+            remove_partial(temp_marker);
+        }
+    }
+
+    result
+}
+
+/// Lowercase hex digest of the content of a local file
+fn file_digest<D: Digest + Write>(path: &Path) -> Result<String> {
+    let mut local_file = File::open(path)
+        .chain_err(|| format!("Error opening local file '{}'", path.to_string_lossy()))?;
+
+    let mut digest = D::new();
+
+    io::copy(&mut local_file, &mut digest)
+        .chain_err(|| format!("Error hashing local file '{}'", path.to_string_lossy()))?;
+
+    Ok(digest.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Read the expected digest from a checksum sidecar file, either in the
+/// format of md5sum and the like ('<digest>  <file name>') or in the BSD
+/// format ('MD5 (<file name>) = <digest>').
+fn read_sidecar(sftp: &ssh2::Sftp, sidecar_path: &str) -> Result<String> {
+    let remote_file = open_remote(sftp, Path::new(sidecar_path))
+        .chain_err(|| format!("Error opening checksum file '{}'", sidecar_path))?;
+
+    let mut content = String::new();
+
+    remote_file.take(SIDECAR_MAX_SIZE).read_to_string(&mut content)
+        .chain_err(|| format!("Error reading checksum file '{}'", sidecar_path))?;
+
+    let digest = match content.split_once(" = ") {
+        Some((_, digest)) => digest.split_whitespace().next(),
+        None => content.split_whitespace().next()
+    };
+
+    match digest {
+        Some(d) => Ok(d.to_lowercase()),
+        None => bail!("No checksum in checksum file '{}'", sidecar_path)
+    }
+}
+
+/// Modification time of a remote file, the epoch when unknown
+fn modified_time(stat: &FileStat) -> Result<DateTime<Utc>> {
+    let sec = match i64::try_from(stat.mtime.unwrap_or(0)) {
+        Ok(s) => s,
+        Err(e) => return Err(Error::with_chain(e, "Error converting mtime to i64"))
+    };
+
+    Ok(DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(sec, 0), Utc))
+}
+
+fn open_remote(sftp: &ssh2::Sftp, remote_path: &Path) -> Result<ssh2::File> {
+    match sftp.open(remote_path) {
+        Ok(remote_file) => Ok(remote_file),
+        Err(e) => {
+            match e.code() {
+                0 => {
+                    // unknown error, probably a fault in the SFTP connection
+                    Err(ErrorKind::DisconnectedError.into())
+                },
+                2 => Err(ErrorKind::NoSuchFileError.into()),
+                -31 => Err(ErrorKind::NoSuchFileError.into()),
+                _ => Err(Error::with_chain(e, "Error opening remote file"))
+            }
+        }
+    }
+}
+
+pub fn sftp_config(config: &settings::SftpSource) -> SftpConfig {
+    SftpConfig {
+        address: config.address.clone(),
+        username: config.username.clone(),
+        password: config.password.clone(),
+        key_file: config.key_file.clone(),
+        compress: config.compress,
+        known_hosts: config.known_hosts.clone(),
+        host_key_fingerprints: config.host_key_fingerprints.clone(),
+        host_key_policy: HostKeyPolicy::effective(config.host_key_policy, &config.known_hosts, &config.host_key_fingerprints),
+    }
+}
+
+/// Offset from which a download can be resumed using a partial file left by
+/// an earlier attempt, or 0 when it has to start over. A partial file is
+/// only used when it is not larger than the remote file and was written after
+/// the remote file was last modified.
+fn resume_offset(temp_path: &Path, stat: &FileStat, resume_min_size: u64) -> u64 {
+    let remote_size = match stat.size {
+        Some(size) if size >= resume_min_size => size,
+        _ => return 0
+    };
+
+    let metadata = match std::fs::metadata(temp_path) {
+        Ok(m) => m,
+        Err(_) => return 0
+    };
+
+    if metadata.len() > remote_size {
+        return 0
+    }
+
+    if let (Some(mtime), Ok(partial_modified)) = (stat.mtime, metadata.modified()) {
+        if time::UNIX_EPOCH + time::Duration::from_secs(mtime) > partial_modified {
+            return 0
+        }
+    }
+
+    metadata.len()
+}
+
+/// Check the number of bytes downloaded against the size reported by the
+/// scanner and the size of the remote file when it was opened. A difference
+/// usually means that the remote file is still being written.
+fn verify_size(bytes_copied: u64, scanned_size: Option<u64>, remote_size: Option<u64>) -> Result<()> {
+    for expected in scanned_size.iter().chain(remote_size.iter()) {
+        if *expected != bytes_copied {
+            return Err(ErrorKind::SizeMismatchError(*expected, bytes_copied).into())
+        }
+    }
+
+    Ok(())
+}
+
+pub fn remove_partial(temp_path: &Path) {
+    if let Err(e) = std::fs::remove_file(temp_path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Could not remove partial download '{}': {}", temp_path.to_string_lossy(), e);
+        }
     }
 }
 
+/// Sleep for the specified number of milliseconds, returning early with false
+/// when the stop flag is set.
+pub fn sleep_unless_stopped(delay: u64, stop: &AtomicBool) -> bool {
+    let step = time::Duration::from_millis(100);
+    let deadline = time::Instant::now() + time::Duration::from_millis(delay);
+
+    while time::Instant::now() < deadline {
+        if stop.load(Ordering::Relaxed) {
+            return false
+        }
+
+        thread::sleep(std::cmp::min(step, deadline.saturating_duration_since(time::Instant::now())));
+    }
+
+    !stop.load(Ordering::Relaxed)
+}
+
 pub struct SftpDownloader<T>
 where
     T: Persistence,
@@ -44,6 +398,11 @@ where
     pub sftp_source: settings::SftpSource,
     pub persistence: T,
     pub local_storage: LocalStorage<T>,
+    /// Bandwidth limit shared by all download threads of the source
+    pub throttle: Arc<Throttle>,
+    /// Connections of the source, used to find the targets of files of
+    /// redelivered commands
+    pub connections: Vec<settings::Connection>,
 }
 
 impl<T> SftpDownloader<T>
@@ -53,6 +412,7 @@ where
     T: Clone,
     T: 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         stop: Arc<AtomicBool>,
         receiver: Receiver<(u64, SftpDownload)>,
@@ -61,17 +421,13 @@ where
         sender: tokio::sync::mpsc::UnboundedSender<FileEvent>,
         local_storage: LocalStorage<T>,
         persistence: T,
+        throttle: Arc<Throttle>,
+        connections: Vec<settings::Connection>,
     ) -> thread::JoinHandle<Result<()>> {
         thread::spawn(move || -> Result<()> {
             proctitle::set_title("sftp_dl");
 
-            let sftp_config = SftpConfig {
-                address: config.address.clone(),
-                username: config.username.clone(),
-                password: config.password.clone(),
-                key_file: config.key_file.clone(),
-                compress: config.compress
-            };
+            let sftp_config = sftp_config(&config);
 
             let connect_result = SftpConnection::connect_loop(sftp_config.clone(), stop.clone());
 
@@ -80,49 +436,37 @@ where
                     debug!("SFTP connection to {} established", sftp_config.address);
                     Arc::new(RefCell::new(c))
                 },
-                Err(e) => return Err(Error::with_chain(e, "SFTP connect failed"))
+                Err(e) => {
+                    error!("[E01011] SFTP connect to {} for source '{}' failed: {}", sftp_config.address, config.name, e);
+                    return Err(Error::with_chain(e, "SFTP connect failed"))
+                }
             };
 
             let mut sftp_downloader = SftpDownloader {
                 sftp_source: config.clone(),
                 persistence: persistence,
                 local_storage: local_storage.clone(),
+                throttle,
+                connections: connections.into_iter().filter(|c| c.source == config.name).collect(),
             };
 
             let timeout = time::Duration::from_millis(500);
 
-            // Take SFTP download commands from the queue until the stop flag is set and
-            // the command channel is empty.
-            while !(stop.load(Ordering::Relaxed) && receiver.is_empty()) {
+            // Take SFTP download commands from the queue until the stop flag is set.
+            while !stop.load(Ordering::Relaxed) {
                 let receive_result = receiver.recv_timeout(timeout);
 
                 match receive_result {
                     Ok((delivery_tag, command)) => {
-                        let download_result = retry(Fixed::from_millis(1000), || {
-                            match sftp_downloader.handle(sftp_connection.clone(), &command) {
-                                Ok(file_event) => OperationResult::Ok(file_event),
-                                Err(e) => {
-                                    match e {
-                                        Error(ErrorKind::DisconnectedError, _) => {
-                                            info!("Sftp connection disconnected, reconnecting");
-                                            let connect_result = SftpConnection::connect_loop(sftp_config.clone(), stop.clone());
-
-                                            match connect_result {
-                                                Ok(c) => {
-                                                    sftp_connection.replace(c);
-                                                    info!("Sftp connection reconnected");
-                                                    OperationResult::Retry(e)
-                                                },
-                                                Err(er) => {
-                                                    OperationResult::Err(Error::with_chain(er, "Error reconnecting SFTP"))
-                                                }
-                                            }
-                                        },
-                                        _ => OperationResult::Err(e)
-                                    }
-                                }
+                        if sftp_downloader.already_downloaded(&sftp_connection, &command) {
+                            if let Err(e) = ack_sender.try_send(MessageResponse::Ack{delivery_tag}) {
+                                error!("Error sending message ack to channel: {}", e);
                             }
-                        });
+
+                            continue;
+                        }
+
+                        let download_result = sftp_downloader.download_with_retry(&sftp_connection, &sftp_config, &command, &stop);
 
                         match download_result {
                             Ok(file_event) => {
@@ -149,9 +493,30 @@ where
                                         error!("Error notifying consumers of new file: {}", e);
                                     }
                                 }
-                            }
-                            Err(e) => {
-                                let send_result = ack_sender.try_send(MessageResponse::Nack{delivery_tag});
+                            },
+                            Err(DownloadFailure::Drop(msg)) => {
+                                if let Err(e) = ack_sender.try_send(MessageResponse::Ack{delivery_tag}) {
+                                    error!("Error sending message ack to channel: {}", e);
+                                }
+
+                                info!("Dropped download command for <{}> '{}': {}", config.name, &command.path, msg);
+                            },
+                            Err(DownloadFailure::Requeue(msg)) => {
+                                if let Err(e) = ack_sender.try_send(MessageResponse::Requeue{delivery_tag}) {
+                                    error!("Error sending message requeue to channel: {}", e);
+                                }
+
+                                warn!("Requeued download command for <{}> '{}': {}", config.name, &command.path, msg);
+                            },
+                            Err(DownloadFailure::Fail(msg, attempts)) => {
+                                // A download that was interrupted by a shutdown is retried later
+                                let response = if stop.load(Ordering::Relaxed) {
+                                    MessageResponse::Requeue{delivery_tag}
+                                } else {
+                                    MessageResponse::Nack{delivery_tag, error: msg.clone(), attempts: Some(attempts)}
+                                };
+
+                                let send_result = ack_sender.try_send(response);
 
                                 match send_result {
                                     Ok(_) => {
@@ -163,16 +528,6 @@ where
 
                                 }
 
-                                let msg = match e {
-                                    retry::Error::<Error>::Operation { error, total_delay: _, tries: _ } => {
-                                        let msg_list: Vec<String> = error.iter().map(|sub_err| sub_err.to_string()).collect();
-                                        msg_list.join(": ").to_string()
-                                    },
-                                    retry::Error::Internal(int) => {
-                                        int
-                                    },
-                                };
-
                                 error!(
                                     "[E01003] Error downloading '{}': {}",
                                     &command.path, msg
@@ -182,7 +537,7 @@ where
                     },
                     Err(e) => {
                         match e {
-                            RecvTimeoutError::Timeout => (),
+                            RecvTimeoutError::Timeout => sftp_downloader.throttle.tick(),
                             RecvTimeoutError::Disconnected => {
                                 // If the stop flag was set, the other side of the channel was dropped because of that, otherwise return an error
                                 if stop.load(Ordering::Relaxed) {
@@ -198,16 +553,247 @@ where
                 }
             }
 
+            // Commands that were not started yet are put back on the queue
+            while let Ok((delivery_tag, command)) = receiver.try_recv() {
+                debug!("Requeueing download command {}", command);
+
+                if let Err(e) = ack_sender.try_send(MessageResponse::Requeue{delivery_tag}) {
+                    error!("Error sending message requeue to channel: {}", e);
+                }
+            }
+
             debug!("SFTP source stream '{}' ended", config.name);
 
             Ok(())
         })
     }
 
-    pub fn handle(&mut self, sftp_connection: Arc<RefCell<SftpConnection>>, msg: &SftpDownload) -> Result<FileEvent> {
-        let remote_path = Path::new(&msg.path);
+    /// Check if the file of a command was downloaded before, in which case the
+    /// command is a redelivery, e.g. after a lost ack, and must not result in
+    /// a second download and dispatch. When configured, deliveries are
+    /// scheduled for the targets that did not receive the file yet.
+    ///
+    /// The post-download action is executed when the earlier delivery did not
+    /// get to it. The marker needs no such check, it is handled before the
+    /// download is recorded.
+    fn already_downloaded(&self, sftp_connection: &Arc<RefCell<SftpConnection>>, command: &SftpDownload) -> bool {
+        let record = match self.persistence.get_sftp_download(command.id) {
+            Ok(Some(record)) => record,
+            Ok(None) => return false,
+            Err(e) => {
+                warn!("Could not read download record of <{}> '{}': {}", self.sftp_source.name, &command.path, e);
+                return false
+            }
+        };
+
+        let (file_id, local_path) = match (record.state.as_str(), record.file_id, record.local_path.clone()) {
+            ("downloaded", Some(file_id), Some(local_path)) => (file_id, local_path),
+            _ => return false
+        };
+
+        info!(
+            "Skipping redelivered command for <{}> '{}', already downloaded to '{}'",
+            self.sftp_source.name, &command.path, local_path.to_string_lossy()
+        );
+
+        metrics::REDELIVERED_COMMANDS_COUNTER
+            .with_label_values(&[&self.sftp_source.name])
+            .inc();
+
+        if record.post_download_pending {
+            self.post_download(&sftp_connection.borrow().sftp, command);
+        }
+
+        if self.sftp_source.redispatch_redelivered {
+            let source_directory = self.local_storage.source_directory(&self.sftp_source.name);
+            let size = record.size.unwrap_or_default() as u64;
+            let modified = record.modified.unwrap_or_else(Utc::now);
+
+            let missing = self.connections.iter()
+                .filter(|c| match &c.filter {
+                    Some(f) => f.stored_file_matches(&source_directory, &local_path, size, modified),
+                    None => true
+                })
+                .filter(|c| !record.dispatched.contains(&c.target));
 
-        let localize_result = self.local_storage.local_path(&self.sftp_source.name, &remote_path, &Path::new("/"));
+            for connection in missing {
+                debug!("Scheduling delivery of file {} to target '{}'", file_id, &connection.target);
+
+                if let Err(e) = self.persistence.schedule_delivery(&connection.target, file_id, "Redelivered command") {
+                    error!("Could not schedule delivery of file {} to target '{}': {}", file_id, &connection.target, e);
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Download the file of a command, retrying failed attempts according to
+    /// the retry policy of the source. Every failed attempt is recorded on the
+    /// download record, so that the attempt count survives redeliveries.
+    fn download_with_retry(
+        &mut self,
+        sftp_connection: &Arc<RefCell<SftpConnection>>,
+        sftp_config: &SftpConfig,
+        command: &SftpDownload,
+        stop: &Arc<AtomicBool>,
+    ) -> std::result::Result<FileEvent, DownloadFailure> {
+        let policy = self.sftp_source.retry.clone();
+        let mut local_attempts: i32 = 0;
+
+        loop {
+            self.set_state(command, SftpDownloadState::Downloading, None);
+
+            let e = match self.handle(sftp_connection.clone(), command, stop) {
+                Ok(file_event) => return Ok(file_event),
+                Err(e) => e
+            };
+
+            let kind = error_kind(&e);
+            let msg = error_message(&e);
+
+            local_attempts += 1;
+
+            let attempts = match self.persistence.record_sftp_download_attempt(command.id, &msg) {
+                Ok(attempts) => attempts,
+                Err(pe) => {
+                    warn!("Could not record download attempt of <{}> '{}': {}", self.sftp_source.name, &command.path, pe);
+                    local_attempts
+                }
+            };
+
+            match policy.action(kind) {
+                settings::RetryAction::Drop => {
+                    let state = match kind {
+                        settings::DownloadErrorKind::NoSuchFile => SftpDownloadState::Vanished,
+                        _ => SftpDownloadState::Failed
+                    };
+
+                    self.discard_partial(command);
+                    self.set_state(command, state, Some(&msg));
+
+                    return Err(DownloadFailure::Drop(msg))
+                },
+                settings::RetryAction::Fail => {
+                    self.set_final_state(command, &msg, stop);
+
+                    return Err(DownloadFailure::Fail(msg, attempts))
+                },
+                settings::RetryAction::Retry if attempts >= policy.max_attempts && kind.is_connection_error() => {
+                    // Not the fault of the file, so the command is not failed.
+                    // Requeueing resets the attempt count of the record.
+                    self.set_state(command, SftpDownloadState::Queued, Some(&msg));
+
+                    return Err(DownloadFailure::Requeue(format!("{} (requeued after {} attempts)", msg, attempts)))
+                },
+                settings::RetryAction::Retry if attempts >= policy.max_attempts => {
+                    let msg = format!("{} (giving up after {} attempts)", msg, attempts);
+
+                    self.set_final_state(command, &msg, stop);
+
+                    return Err(DownloadFailure::Fail(msg, attempts))
+                },
+                settings::RetryAction::Retry => {
+                    let delay = policy.delay(attempts);
+
+                    info!(
+                        "Download attempt {} of <{}> '{}' failed, retrying in {} ms: {}",
+                        attempts, self.sftp_source.name, &command.path, delay, &msg
+                    );
+
+                    if !sleep_unless_stopped(delay, stop) {
+                        self.set_final_state(command, &msg, stop);
+
+                        return Err(DownloadFailure::Fail(msg, attempts))
+                    }
+
+                    if kind == settings::DownloadErrorKind::Disconnected {
+                        info!("Sftp connection disconnected, reconnecting");
+
+                        match SftpConnection::connect_loop(sftp_config.clone(), stop.clone()) {
+                            Ok(c) => {
+                                sftp_connection.replace(c);
+                                info!("Sftp connection reconnected");
+                            },
+                            Err(er) => {
+                                let msg = format!("Error reconnecting SFTP: {}", er);
+
+                                self.set_final_state(command, &msg, stop);
+
+                                return Err(DownloadFailure::Fail(msg, attempts))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Execute the post-download action on the remote file of a downloaded
+    /// command, or defer it until the file is dispatched when configured.
+    fn post_download(&self, sftp: &ssh2::Sftp, msg: &SftpDownload) {
+        let action = msg.post_download_action();
+
+        if action != PostDownloadAction::None && self.sftp_source.deferred_actions.is_some() {
+            let defer_result = serde_json::to_string(&action)
+                .map_err(|e| e.to_string())
+                .and_then(|json| self.persistence.insert_deferred_action(msg.id, &json).map_err(|e| e.to_string()));
+
+            match defer_result {
+                Ok(()) => debug!("Deferred post-download action on <{}> '{}'", self.sftp_source.name, msg.path),
+                Err(e) => {
+                    error!(
+                        "[E01018] Could not defer post-download action on <{}> '{}': {}",
+                        self.sftp_source.name, msg.path, &e
+                    );
+
+                    metrics::POST_DOWNLOAD_FAILURES_COUNTER
+                        .with_label_values(&[&self.sftp_source.name])
+                        .inc();
+
+                    if let Err(pe) = self.persistence.set_sftp_download_post_action(msg.id, None, Some(&e)) {
+                        warn!("Could not record post-download action of <{}> '{}': {}", self.sftp_source.name, msg.path, pe);
+                    }
+                }
+            }
+        } else if action != PostDownloadAction::None {
+            let action_result = post_download::execute(sftp, &action, Path::new(&msg.path));
+
+            // The download itself succeeded, so a failing action is recorded
+            // with the download instead of failing the command.
+            let record_result = match action_result {
+                Ok(outcome) => {
+                    debug!("Post-download action on <{}> '{}': {}", self.sftp_source.name, msg.path, outcome.as_deref().unwrap_or("none"));
+
+                    self.persistence.set_sftp_download_post_action(msg.id, outcome.as_deref(), None)
+                },
+                Err(e) => {
+                    let msg_list: Vec<String> = e.iter().map(|sub_err| sub_err.to_string()).collect();
+                    let error = msg_list.join(": ");
+
+                    error!(
+                        "[E01018] Post-download action on <{}> '{}' failed: {}",
+                        self.sftp_source.name, msg.path, &error
+                    );
+
+                    metrics::POST_DOWNLOAD_FAILURES_COUNTER
+                        .with_label_values(&[&self.sftp_source.name])
+                        .inc();
+
+                    self.persistence.set_sftp_download_post_action(msg.id, None, Some(&error))
+                }
+            };
+
+            if let Err(e) = record_result {
+                warn!("Could not record post-download action of <{}> '{}': {}", self.sftp_source.name, msg.path, e);
+            }
+        }
+    }
+
+    /// Final path of a download in the local storage and the path of the
+    /// temporary file it is written to.
+    fn download_paths(&self, msg: &SftpDownload) -> Result<(PathBuf, PathBuf)> {
+        let localize_result = self.local_storage.local_path(&self.sftp_source.name, &Path::new(&msg.path), &Path::new("/"));
 
         let local_path = match localize_result {
             Ok(p) => p,
@@ -216,6 +802,197 @@ where
             }
         };
 
+        let temp_path = temp_path(&local_path)?;
+
+        Ok((local_path, temp_path))
+    }
+
+    /// Remove a partial file that was kept for resuming the download
+    fn discard_partial(&self, command: &SftpDownload) {
+        if let Ok((_, temp_path)) = self.download_paths(command) {
+            remove_partial(&temp_path);
+        }
+    }
+
+    fn set_state(&self, command: &SftpDownload, state: SftpDownloadState, error: Option<&str>) {
+        if let Err(e) = self.persistence.set_sftp_download_state(command.id, state, error) {
+            warn!("Could not record state '{}' of download <{}> '{}': {}", state, self.sftp_source.name, &command.path, e);
+        }
+    }
+
+    /// Record the state of a command that will be rejected: queued again when
+    /// it is requeued because of a shutdown, keeping any partial file for a
+    /// resume, and failed otherwise.
+    fn set_final_state(&self, command: &SftpDownload, error: &str, stop: &AtomicBool) {
+        let state = if stop.load(Ordering::Relaxed) {
+            SftpDownloadState::Queued
+        } else {
+            self.discard_partial(command);
+
+            SftpDownloadState::Failed
+        };
+
+        self.set_state(command, state, Some(error));
+    }
+
+    /// Verify a downloaded file against the checksum in its sidecar file and
+    /// return the checksum as '<algorithm>:<digest>'. A file that does not
+    /// match is discarded, or moved to the quarantine directory and recorded.
+    #[allow(clippy::too_many_arguments)]
+    fn verify_checksum(
+        &self,
+        sftp: &ssh2::Sftp,
+        verification: &settings::ChecksumVerification,
+        msg: &SftpDownload,
+        temp_path: &Path,
+        sha256: &str,
+        size: u64,
+        stat: &FileStat,
+    ) -> Result<String> {
+        let expected = read_sidecar(sftp, &verification.sidecar_path(&msg.path))?;
+
+        let actual = match verification.algorithm {
+            settings::ChecksumAlgorithm::Md5 => file_digest::<Md5>(temp_path)?,
+            settings::ChecksumAlgorithm::Sha1 => file_digest::<Sha1>(temp_path)?,
+            settings::ChecksumAlgorithm::Sha256 => sha256.to_string(),
+        };
+
+        let checksum = format!("{}:{}", verification.algorithm.as_str(), &expected);
+
+        if actual == expected {
+            debug!("Verified {} of <{}> '{}'", &checksum, self.sftp_source.name, msg.path);
+
+            return Ok(checksum)
+        }
+
+        warn!(
+            "[E01020] Checksum mismatch of <{}> '{}': {} where {} was expected",
+            self.sftp_source.name, msg.path, &actual, &checksum
+        );
+
+        let directory = match &verification.on_mismatch {
+            settings::ChecksumMismatchAction::Retry => {
+                metrics::CHECKSUM_MISMATCH_COUNTER
+                    .with_label_values(&[&self.sftp_source.name, "retry"])
+                    .inc();
+
+                return Err(ErrorKind::ChecksumMismatchError(expected, actual).into())
+            },
+            settings::ChecksumMismatchAction::Quarantine { directory } => directory
+        };
+
+        metrics::CHECKSUM_MISMATCH_COUNTER
+            .with_label_values(&[&self.sftp_source.name, "quarantine"])
+            .inc();
+
+        let file_name = Path::new(&msg.path).file_name()
+            .chain_err(|| format!("No file name in remote path '{}'", &msg.path))?;
+
+        // Files with the same name from other sources, directories or
+        // downloads are kept apart by the source name and download id.
+        let quarantine_directory = directory.join(&self.sftp_source.name).join(msg.id.to_string());
+
+        std::fs::create_dir_all(&quarantine_directory)
+            .chain_err(|| format!("Error creating quarantine directory '{}'", quarantine_directory.to_string_lossy()))?;
+
+        let quarantine_path = quarantine_directory.join(file_name);
+
+        // A hard link fails instead of replacing an earlier quarantined file
+        match std::fs::hard_link(temp_path, &quarantine_path) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                // A rerun of the same command, e.g. after a redelivery, finds
+                // the file it quarantined before.
+                if file_digest::<Sha256>(&quarantine_path)? != sha256 {
+                    bail!("Quarantined file '{}' exists with other content", quarantine_path.to_string_lossy());
+                }
+
+                debug!("<{}> '{}' was already quarantined", self.sftp_source.name, msg.path);
+            },
+            Err(e) => {
+                return Err(Error::with_chain(e, format!("Error moving '{}' to '{}'", temp_path.to_string_lossy(), quarantine_path.to_string_lossy())))
+            }
+        }
+
+        remove_partial(temp_path);
+
+        let recorded = self.persistence.get_sftp_download(msg.id)
+            .chain_err(|| ErrorKind::PersistenceError)?
+            .is_some_and(|record| record.file_id.is_some() && record.local_path.as_deref() == Some(quarantine_path.as_path()));
+
+        if !recorded {
+            let file_size = i64::try_from(size)
+                .chain_err(|| "Error converting bytes copied to i64")?;
+
+            let file_id = self.persistence.insert_file(
+                &self.sftp_source.name, &quarantine_path.to_string_lossy(), &modified_time(stat)?, file_size, Some(sha256.to_string())
+            ).chain_err(|| ErrorKind::PersistenceError)?;
+
+            self.persistence.set_file_checksum(file_id, &checksum, "mismatch")
+                .chain_err(|| ErrorKind::PersistenceError)?;
+
+            self.persistence.set_sftp_download_file(msg.id, file_id, SftpDownloadState::Failed)
+                .chain_err(|| ErrorKind::PersistenceError)?;
+        }
+
+        Err(ErrorKind::QuarantinedError(quarantine_path.to_string_lossy().to_string()).into())
+    }
+
+    /// Download the completion marker of a file to a temporary path next to
+    /// the local path of the file. Returns the temporary path and the final
+    /// path of the marker, to which it is moved together with the file.
+    fn fetch_marker(&self, sftp: &ssh2::Sftp, marker: &Marker, local_path: &Path) -> Result<(PathBuf, PathBuf)> {
+        let remote_path = Path::new(&marker.path);
+
+        let file_name = remote_path.file_name()
+            .chain_err(|| format!("No file name in marker path '{}'", &marker.path))?;
+
+        let local_marker = local_path.with_file_name(file_name);
+        let temp_marker = temp_path(&local_marker)?;
+
+        let download_result = open_remote(sftp, remote_path)
+            .and_then(|mut remote_file| download_to(&mut remote_file, &temp_marker, 0, &self.throttle));
+
+        if let Err(e) = download_result {
+            remove_partial(&temp_marker);
+
+            return Err(e).chain_err(|| format!("Error downloading marker '{}'", &marker.path));
+        }
+
+        debug!("Downloaded marker <{}> '{}'", self.sftp_source.name, &marker.path);
+
+        Ok((temp_marker, local_marker))
+    }
+
+    /// Remove the completion marker of a downloaded file. A failing delete
+    /// does not fail the command, but is recorded with the download, like a
+    /// failing post-download action.
+    fn delete_marker(&self, sftp: &ssh2::Sftp, msg: &SftpDownload, marker: &Marker) {
+        let record_result = match sftp.unlink(Path::new(&marker.path)) {
+            Ok(()) => {
+                debug!("Removed marker <{}> '{}'", self.sftp_source.name, &marker.path);
+
+                self.persistence.set_sftp_download_marker(msg.id, Some("removed"), None)
+            },
+            Err(e) => {
+                let error = format!("Error removing marker '{}': {}", &marker.path, e);
+
+                warn!("Could not remove marker <{}> '{}': {}", self.sftp_source.name, &marker.path, e);
+
+                self.persistence.set_sftp_download_marker(msg.id, None, Some(&error))
+            }
+        };
+
+        if let Err(e) = record_result {
+            warn!("Could not record marker of <{}> '{}': {}", self.sftp_source.name, msg.path, e);
+        }
+    }
+
+    pub fn handle(&mut self, sftp_connection: Arc<RefCell<SftpConnection>>, msg: &SftpDownload, stop: &Arc<AtomicBool>) -> Result<FileEvent> {
+        let remote_path = Path::new(&msg.path);
+
+        let (local_path, temp_path) = self.download_paths(msg)?;
+
         match msg.size {
             Some(size) => {
                 debug!(
@@ -234,39 +1011,12 @@ where
             }
         }
 
-        let (copy_result, hash, stat) = {
-            let borrow = sftp_connection.borrow();
-            
-            let open_result = borrow.sftp.open(&remote_path);
-
-            let mut remote_file = match open_result {
-                Ok(remote_file) => remote_file,
-                Err(e) => {
-                    match e.code() {
-                        0 => {
-                            // unknown error, probably a fault in the SFTP connection
-                            return Err(ErrorKind::DisconnectedError.into())
-                        },
-                        2 => {
-                            let delete_result = self.persistence.delete_sftp_download_file(msg.id);
+        let started = time::Instant::now();
 
-                            match delete_result {
-                                Ok(_) => return Err(ErrorKind::NoSuchFileError.into()),
-                                Err(e) => return Err(Error::with_chain(e, "Error removing record of non-existent remote file"))
-                            }
-                        },
-                        -31 => {
-                            let delete_result = self.persistence.delete_sftp_download_file(msg.id);
+        let (copy_result, stat, offset, streams) = {
+            let borrow = sftp_connection.borrow();
 
-                            match delete_result {
-                                Ok(_) => return Err(ErrorKind::NoSuchFileError.into()),
-                                Err(e) => return Err(Error::with_chain(e, "Error removing record of non-existent remote file"))
-                            }
-                        },
-                        _ => return Err(Error::with_chain(e, "Error opening remote file"))
-                    }
-                }
-            };
+            let mut remote_file = open_remote(&borrow.sftp, remote_path)?;
 
             let stat_result = remote_file.stat();
 
@@ -296,89 +1046,148 @@ where
                 }    
             }
 
-            let mut local_file = File::create(&local_path)
-                .chain_err(|| format!("Error creating local file '{}'", local_path.to_string_lossy()))?;
+            let offset = resume_offset(&temp_path, &stat, self.sftp_source.resume_min_size);
 
-            let mut sha256 = Sha256::new();
+            if offset > 0 {
+                info!(
+                    "Resuming download of <{}> '{}' at {} bytes",
+                    self.sftp_source.name, msg.path, offset
+                );
+            }
+
+            // A resumed download continues in a single stream
+            let streams = match (&self.sftp_source.parallel, stat.size) {
+                (Some(parallel), Some(size)) if offset == 0 && size >= parallel.min_size => parallel.streams,
+                _ => 1
+            };
 
-            let mut tee_reader = TeeReader::new(&mut remote_file, &mut sha256);
+            let copy_result = if streams > 1 {
+                debug!(
+                    "Downloading <{}> '{}' in {} parallel streams",
+                    self.sftp_source.name, msg.path, streams
+                );
 
-            (
-                io::copy(&mut tee_reader, &mut local_file),
-                format!("{:x}", sha256.finalize()),
-                stat
-            )
+                download_parallel(&sftp_config(&self.sftp_source), remote_path, stat.size.unwrap_or(0), streams, &temp_path, &self.throttle, stop)
+            } else {
+                download_to(&mut remote_file, &temp_path, offset, &self.throttle)
+            };
+
+            (copy_result, stat, offset, streams)
         };
 
-        match copy_result {
-            Ok(bytes_copied) => {
-                info!(
-                    "Downloaded <{}> '{}' {} bytes",
-                    self.sftp_source.name, msg.path, bytes_copied
-                );
+        let verify_result = copy_result.and_then(|(bytes_copied, hash)| {
+            verify_size(bytes_copied, msg.size, stat.size)?;
 
-                let file_size = match i64::try_from(bytes_copied) {
-                    Ok(size) => size,
-                    Err(e) => return Err(Error::with_chain(e, "Error converting bytes copied to i64"))
-                };
+            let checksum = match &self.sftp_source.checksum {
+                Some(verification) => {
+                    let sftp = &sftp_connection.borrow().sftp;
 
-                let mtime = match stat.mtime {
-                    Some(t) => t,
-                    None => 0
-                };
+                    Some(self.verify_checksum(sftp, verification, msg, &temp_path, &hash, bytes_copied, &stat)?)
+                },
+                None => None
+            };
 
-				let sec = match i64::try_from(mtime) {
-                    Ok(s) => s,
-                    Err(e) => return Err(Error::with_chain(e, "Error converting mtime to i64"))
-                };
-				let nsec = 0;
+            // The marker is fetched before anything is moved into the local
+            // storage, so that a failing marker leaves no unrecorded file.
+            let fetched_marker = match &msg.marker {
+                Some(marker) if marker.action == MarkerAction::Download => {
+                    Some(self.fetch_marker(&sftp_connection.borrow().sftp, marker, &local_path)?)
+                },
+                _ => None
+            };
 
-                let modified = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(sec, nsec), Utc);
+            place_download(&temp_path, &local_path, fetched_marker.as_ref())?;
 
-                let file_id = match self.persistence.insert_file(
-                    &self.sftp_source.name, &local_path.to_string_lossy(), &modified, file_size, Some(hash)
-                ) {
-                    Ok(id) => id,
-                    Err(e) => return Err(ErrorKind::PersistenceError.into())
-                };
+            Ok((bytes_copied, hash, checksum, fetched_marker))
+        });
 
-                let set_result = self.persistence.set_sftp_download_file(msg.id, file_id);
+        let (bytes_copied, hash, checksum, fetched_marker) = match verify_result {
+            Ok(r) => r,
+            Err(e) => {
+                // The partial file of a large download is kept when the
+                // connection broke, so that the next attempt can resume it.
+                let resumable = matches!(e.kind(), ErrorKind::DisconnectedError)
+                    && streams == 1
+                    && stat.size.is_some_and(|size| size >= self.sftp_source.resume_min_size);
 
-                match set_result {
-                    Ok(_) => {},
-                    Err(_) => return Err(ErrorKind::PersistenceError.into()),
+                if !resumable {
+                    remove_partial(&temp_path);
                 }
 
-                metrics::FILE_DOWNLOAD_COUNTER_VEC
-                    .with_label_values(&[&self.sftp_source.name])
-                    .inc();
-                metrics::BYTES_DOWNLOADED_COUNTER_VEC
-                    .with_label_values(&[&self.sftp_source.name])
-                    .inc_by(bytes_copied);
+                return Err(e)
+            }
+        };
 
-                if msg.remove {
-                    let unlink_result = sftp_connection.borrow().sftp.unlink(&remote_path);
+        info!(
+            "Downloaded <{}> '{}' {} bytes",
+            self.sftp_source.name, msg.path, bytes_copied
+        );
 
-                    match unlink_result {
-                        Ok(_) => {
-                            debug!("Removed <{}> '{}'", self.sftp_source.name, msg.path);
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error removing <{}> '{}': {}",
-                                self.sftp_source.name, msg.path, e
-                            );
-                        }
-                    }
-                }
+        let elapsed = started.elapsed().as_secs_f64();
 
-                Ok(FileEvent {
-                    file_id: file_id,
-                    source_name: self.sftp_source.name.clone(),
-                    path: local_path,
-                })
+        if elapsed > 0.0 {
+            let mode = if streams > 1 { "parallel" } else { "single" };
+
+            metrics::SFTP_DOWNLOAD_THROUGHPUT_HISTOGRAM
+                .with_label_values(&[&self.sftp_source.name, mode])
+                .observe((bytes_copied - offset) as f64 / elapsed);
+        }
+
+        let file_size = match i64::try_from(bytes_copied) {
+            Ok(size) => size,
+            Err(e) => return Err(Error::with_chain(e, "Error converting bytes copied to i64"))
+        };
+
+        let modified = modified_time(&stat)?;
+
+        let file_id = match self.persistence.insert_file(
+            &self.sftp_source.name, &local_path.to_string_lossy(), &modified, file_size, Some(hash)
+        ) {
+            Ok(id) => id,
+            Err(e) => return Err(ErrorKind::PersistenceError.into())
+        };
+
+        if let Some(checksum) = checksum {
+            self.persistence.set_file_checksum(file_id, &checksum, "verified")
+                .chain_err(|| ErrorKind::PersistenceError)?;
+        }
+
+        // The marker is dealt with before the download is recorded as done,
+        // so that a redelivered command needs no check on it.
+        match (&msg.marker, fetched_marker) {
+            (Some(_), Some((_, local_marker))) => {
+                let outcome = format!("downloaded to '{}'", local_marker.to_string_lossy());
+
+                if let Err(e) = self.persistence.set_sftp_download_marker(msg.id, Some(&outcome), None) {
+                    warn!("Could not record marker of <{}> '{}': {}", self.sftp_source.name, msg.path, e);
+                }
+            },
+            (Some(marker), None) if marker.action == MarkerAction::Delete => {
+                self.delete_marker(&sftp_connection.borrow().sftp, msg, marker);
             },
-            Err(e) => Err(Error::with_chain(e, "Error copying file")),
+            _ => {}
+        }
+
+        let set_result = self.persistence.set_sftp_download_file(msg.id, file_id, SftpDownloadState::Downloaded);
+
+        match set_result {
+            Ok(_) => {},
+            Err(_) => return Err(ErrorKind::PersistenceError.into()),
         }
+
+        metrics::FILE_DOWNLOAD_COUNTER_VEC
+            .with_label_values(&[&self.sftp_source.name])
+            .inc();
+        metrics::BYTES_DOWNLOADED_COUNTER_VEC
+            .with_label_values(&[&self.sftp_source.name])
+            .inc_by(bytes_copied);
+
+        self.post_download(&sftp_connection.borrow().sftp, msg);
+
+        Ok(FileEvent {
+            file_id: file_id,
+            source_name: self.sftp_source.name.clone(),
+            path: local_path,
+        })
     }
 }
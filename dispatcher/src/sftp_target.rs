@@ -0,0 +1,284 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio_postgres::Socket;
+use postgres::tls::{MakeTlsConnect, TlsConnect};
+
+use cortex_core::ssh2::{self, FileStat, RenameFlags};
+
+use cortex_core::sftp_connection::{HostKeyPolicy, SftpConfig, SftpConnection};
+
+use crate::event::FileEvent;
+use crate::metrics;
+use crate::persistence::PostgresAsyncPersistence;
+use crate::settings;
+use crate::throttle::{Throttle, ThrottledReader};
+
+error_chain! {
+    errors {
+        DisconnectedError
+        RemoteFileExistsError(path: String) {
+            description("remote file already exists")
+            display("remote file '{}' already exists with a different size and overwrite is disabled", path)
+        }
+    }
+}
+
+/// Translate an SFTP error into a DisconnectedError when it indicates a broken
+/// connection, so that the upload is retried on a new connection.
+fn sftp_error(e: ssh2::Error, msg: String) -> Error {
+    match e.code() {
+        // unknown error, probably a fault in the SFTP connection
+        0 => ErrorKind::DisconnectedError.into(),
+        // SFTP protocol error
+        -31 => ErrorKind::DisconnectedError.into(),
+        _ => Error::with_chain(e, msg)
+    }
+}
+
+fn sftp_config(settings: &settings::SftpTarget) -> SftpConfig {
+    SftpConfig {
+        address: settings.address.clone(),
+        username: settings.username.clone(),
+        password: settings.password.clone(),
+        key_file: settings.key_file.clone(),
+        compress: settings.compress,
+        known_hosts: settings.known_hosts.clone(),
+        host_key_fingerprints: settings.host_key_fingerprints.clone(),
+        host_key_policy: HostKeyPolicy::effective(settings.host_key_policy, &settings.known_hosts, &settings.host_key_fingerprints),
+    }
+}
+
+/// Upload a file to the target directory under a temporary name and rename it
+/// to its final name when complete, so that consumers never see partial files.
+///
+/// Without overwrite, an existing remote file of the same size is taken to be
+/// an earlier delivery of the file, e.g. one of which the notification
+/// failed, and is left alone.
+fn put_file(settings: &settings::SftpTarget, sftp_connection: &SftpConnection, file_event: &FileEvent, throttle: &Throttle) -> Result<(PathBuf, u64)> {
+    let sftp = &sftp_connection.sftp;
+
+    let file_name = match file_event.path.file_name() {
+        Some(f) => f.to_string_lossy().to_string(),
+        None => bail!("No file name from file event path '{}'", file_event.path.to_string_lossy())
+    };
+
+    let remote_path = settings.directory.join(&file_name);
+    let temp_path = settings.directory.join(format!(".{}.part", &file_name));
+    let remote_path_str = remote_path.to_string_lossy().to_string();
+
+    let local_file = File::open(&file_event.path)
+        .chain_err(|| format!("Error opening local file '{}'", file_event.path.to_string_lossy()))?;
+
+    if !settings.overwrite {
+        if let Ok(stat) = sftp.stat(&remote_path) {
+            let local_size = local_file.metadata()
+                .chain_err(|| format!("Error reading metadata of '{}'", file_event.path.to_string_lossy()))?
+                .len();
+
+            if stat.size != Some(local_size) {
+                bail!(ErrorKind::RemoteFileExistsError(remote_path_str));
+            }
+
+            info!("Remote file '{}' of target '{}' already delivered", &remote_path_str, &settings.name);
+
+            return Ok((remote_path, 0))
+        }
+    }
+
+    let reader = ThrottledReader::new(local_file, throttle);
+
+    let write_result = write_file(settings, sftp, reader, &temp_path, &remote_path);
+
+    // Partial or unrenamed uploads are not left behind on the server
+    if write_result.is_err() {
+        remove_temp(sftp, &temp_path);
+    }
+
+    write_result.map(|bytes_copied| (remote_path, bytes_copied))
+}
+
+/// Write the contents of `reader` to `temp_path` and rename it to
+/// `remote_path`, returning the number of bytes written.
+fn write_file<R: io::Read>(settings: &settings::SftpTarget, sftp: &ssh2::Sftp, mut reader: R, temp_path: &Path, remote_path: &Path) -> Result<u64> {
+    let bytes_copied = {
+        let mut remote_file = sftp.create(temp_path)
+            .map_err(|e| sftp_error(e, format!("Error creating remote file '{}'", temp_path.to_string_lossy())))?;
+
+        io::copy(&mut reader, &mut remote_file)
+            .chain_err(|| format!("Error uploading to '{}'", temp_path.to_string_lossy()))?
+    };
+
+    if let Some(permissions) = settings.permissions {
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(permissions),
+            atime: None,
+            mtime: None,
+        };
+
+        sftp.setstat(temp_path, stat)
+            .map_err(|e| sftp_error(e, format!("Error setting permissions on '{}'", temp_path.to_string_lossy())))?;
+    }
+
+    if settings.overwrite {
+        replace(sftp, temp_path, remote_path)?;
+    } else {
+        sftp.rename(temp_path, remote_path, None)
+            .map_err(|e| sftp_error(e, format!("Error renaming '{}' to '{}'", temp_path.to_string_lossy(), remote_path.to_string_lossy())))?;
+    }
+
+    Ok(bytes_copied)
+}
+
+/// Remove the temporary file of a failed upload, if it was created
+fn remove_temp(sftp: &ssh2::Sftp, temp_path: &Path) {
+    if let Err(e) = sftp.unlink(temp_path) {
+        // NoSuchFile means that the upload failed before the file was created
+        if e.code() != 2 {
+            warn!("Could not remove partial upload '{}': {}", temp_path.to_string_lossy(), e);
+        }
+    }
+}
+
+/// Atomically replace a remote file by the uploaded temporary file. Servers
+/// that do not support overwriting renames, like those speaking SFTP version
+/// 3, reject the rename when the destination exists, in which case the
+/// destination is removed first.
+fn replace(sftp: &ssh2::Sftp, temp_path: &Path, remote_path: &Path) -> Result<()> {
+    let rename_error = match sftp.rename(temp_path, remote_path, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC)) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+
+    if sftp.stat(remote_path).is_err() {
+        return Err(sftp_error(rename_error, format!("Error renaming '{}' to '{}'", temp_path.to_string_lossy(), remote_path.to_string_lossy())));
+    }
+
+    debug!("Overwriting rename to '{}' rejected, removing the existing file first", remote_path.to_string_lossy());
+
+    // The file may have been removed in the meantime, so NoSuchFile is fine
+    if let Err(e) = sftp.unlink(remote_path) {
+        if e.code() != 2 {
+            return Err(sftp_error(e, format!("Error removing existing remote file '{}'", remote_path.to_string_lossy())));
+        }
+    }
+
+    sftp.rename(temp_path, remote_path, None)
+        .map_err(|e| sftp_error(e, format!("Error renaming '{}' to '{}'", temp_path.to_string_lossy(), remote_path.to_string_lossy())))
+}
+
+/// Upload a file using the provided connection, (re)connecting when there is
+/// no connection yet or when it turns out to be broken.
+pub fn upload(settings: &settings::SftpTarget, sftp_connection: &mut Option<SftpConnection>, file_event: &FileEvent, throttle: &Throttle) -> Result<(PathBuf, u64)> {
+    let mut reconnected = false;
+
+    loop {
+        let connection = match sftp_connection.take() {
+            Some(c) => c,
+            None => {
+                let c = SftpConnection::connect(sftp_config(settings))
+                    .chain_err(|| format!("Error connecting to {}", &settings.address))?;
+
+                debug!("SFTP connection to {} for target '{}' established", &settings.address, &settings.name);
+                reconnected = true;
+                c
+            }
+        };
+
+        let put_result = put_file(settings, &connection, file_event, throttle);
+
+        match put_result {
+            Err(Error(ErrorKind::DisconnectedError, _)) if !reconnected => {
+                info!("SFTP connection of target '{}' disconnected, reconnecting", &settings.name);
+            },
+            Err(Error(ErrorKind::DisconnectedError, _)) => {
+                // The connection is dropped so that the next upload reconnects
+                bail!("SFTP connection to {} disconnected", &settings.address);
+            },
+            result => {
+                *sftp_connection = Some(connection);
+                return result
+            }
+        }
+    }
+}
+
+/// Upload the file of a file event to an SFTP target. The connection is passed
+/// in and returned, so that it can be reused for the next upload. The throttle
+/// is shared by all workers of the target.
+pub async fn handle_file_event<T>(
+    settings: &settings::SftpTarget,
+    sftp_connection: Option<SftpConnection>,
+    file_event: FileEvent,
+    throttle: Arc<Throttle>,
+    persistence: PostgresAsyncPersistence<T>
+) -> (Option<SftpConnection>, std::result::Result<FileEvent, String>)
+where
+    T: MakeTlsConnect<Socket> + Clone + 'static + Sync + Send,
+    T::TlsConnect: Send,
+    T::Stream: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    debug!("FileEvent for {}: '{}'", &settings.name, file_event.path.to_string_lossy());
+
+    let upload_settings = settings.clone();
+    let upload_event = file_event.clone();
+
+    let join_result = tokio::task::spawn_blocking(move || {
+        let mut sftp_connection = sftp_connection;
+        let result = upload(&upload_settings, &mut sftp_connection, &upload_event, &throttle);
+
+        (sftp_connection, result)
+    }).await;
+
+    let (sftp_connection, upload_result) = match join_result {
+        Ok(r) => r,
+        Err(e) => return (None, Err(format!("Upload task failed: {}", e)))
+    };
+
+    let (remote_path, bytes_copied) = match upload_result {
+        Ok(r) => r,
+        Err(e) => {
+            let msg_list: Vec<String> = e.iter().map(|sub_err| sub_err.to_string()).collect();
+
+            error!(
+                "[E01015] Error uploading '{}' to '{}': {}",
+                file_event.path.to_string_lossy(), &settings.name, msg_list.join(": ")
+            );
+
+            return (sftp_connection, Err(msg_list.join(": ")))
+        }
+    };
+
+    info!(
+        "Uploaded '{}' to <{}> '{}' {} bytes",
+        file_event.path.to_string_lossy(), &settings.name, remote_path.to_string_lossy(), bytes_copied
+    );
+
+    metrics::FILE_UPLOAD_COUNTER_VEC
+        .with_label_values(&[&settings.name])
+        .inc();
+    metrics::BYTES_UPLOADED_COUNTER_VEC
+        .with_label_values(&[&settings.name])
+        .inc_by(bytes_copied);
+
+    let insert_result = persistence.insert_dispatched(&settings.name, file_event.file_id).await;
+
+    match insert_result {
+        Ok(_) => debug!("Dispatched to SFTP target"),
+        Err(e) => debug!("Error persisting dispatch: {}", &e)
+    }
+
+    let result_event = FileEvent {
+        file_id: file_event.file_id,
+        source_name: settings.name.clone(),
+        path: remote_path
+    };
+
+    (sftp_connection, Ok(result_event))
+}
@@ -0,0 +1,138 @@
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+
+use prometheus::IntGauge;
+
+use crate::settings::BandwidthWindow;
+
+/// Interval over which the throughput gauge is averaged
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Bucket {
+    /// Bytes that can be transferred without waiting, negative when the
+    /// transfers are ahead of the limit
+    tokens: f64,
+    last_refill: Instant,
+    window_bytes: u64,
+    window_start: Instant,
+}
+
+/// Token bucket that limits the combined throughput of all transfers of a
+/// source or target, and reports the actual throughput in a gauge.
+pub struct Throttle {
+    max_bytes_per_second: Option<u64>,
+    schedule: Vec<BandwidthWindow>,
+    gauge: IntGauge,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(max_bytes_per_second: Option<u64>, schedule: Vec<BandwidthWindow>, gauge: IntGauge) -> Throttle {
+        let now = Instant::now();
+
+        Throttle {
+            max_bytes_per_second,
+            schedule,
+            gauge,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last_refill: now,
+                window_bytes: 0,
+                window_start: now,
+            }),
+        }
+    }
+
+    /// Limit that applies now: that of the first schedule window containing
+    /// the current local time, or the default limit outside all windows.
+    fn current_limit(&self) -> Option<u64> {
+        let now = Local::now().time();
+
+        match self.schedule.iter().find(|w| w.contains(now)) {
+            Some(window) => window.max_bytes_per_second,
+            None => self.max_bytes_per_second,
+        }
+    }
+
+    /// Account for a transfer of `bytes`, blocking as long as the transfers
+    /// are ahead of the limit. The bucket holds at most one second worth of
+    /// tokens, so that an idle period does not allow a long burst.
+    pub fn consume(&self, bytes: u64) {
+        let delay = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+
+            bucket.window_bytes += bytes;
+            self.update_gauge(&mut bucket, now);
+
+            match self.current_limit() {
+                Some(limit) if limit > 0 => {
+                    let rate = limit as f64;
+                    let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+
+                    bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+                    bucket.last_refill = now;
+
+                    if bucket.tokens < 0.0 {
+                        Some(Duration::from_secs_f64(-bucket.tokens / rate))
+                    } else {
+                        None
+                    }
+                },
+                _ => {
+                    bucket.tokens = 0.0;
+                    bucket.last_refill = now;
+                    None
+                }
+            }
+        };
+
+        if let Some(d) = delay {
+            thread::sleep(d);
+        }
+    }
+
+    /// Refresh the throughput gauge, also when no transfers are running
+    pub fn tick(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        self.update_gauge(&mut bucket, Instant::now());
+    }
+
+    fn update_gauge(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.window_start);
+
+        if elapsed >= RATE_INTERVAL {
+            self.gauge.set((bucket.window_bytes as f64 / elapsed.as_secs_f64()) as i64);
+
+            bucket.window_bytes = 0;
+            bucket.window_start = now;
+        }
+    }
+}
+
+/// Reader that passes everything that is read through a throttle
+pub struct ThrottledReader<'a, R> {
+    inner: R,
+    throttle: &'a Throttle,
+}
+
+impl<'a, R> ThrottledReader<'a, R> {
+    pub fn new(inner: R, throttle: &'a Throttle) -> ThrottledReader<'a, R> {
+        ThrottledReader { inner, throttle }
+    }
+}
+
+impl<'a, R: io::Read> io::Read for ThrottledReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        self.throttle.consume(n as u64);
+
+        Ok(n)
+    }
+}
@@ -39,6 +39,7 @@ mod http_server;
 mod metrics;
 mod settings;
 mod sftp_scanner;
+mod stability;
 mod amqp_sender;
 
 use sftp_scanner::Error;
@@ -199,6 +200,11 @@ fn load_settings(config_file: &str) -> Settings {
         }
     };
 
+    if let Err(e) = settings.validate() {
+        error!("Invalid configuration: {}", e);
+        ::std::process::exit(1);
+    }
+
     info!("Configuration loaded");
 
     settings
@@ -1,4 +1,4 @@
-use prometheus::IntCounterVec;
+use prometheus::{IntCounterVec, IntGaugeVec};
 
 lazy_static! {
     pub static ref DIR_SCAN_COUNTER: IntCounterVec = register_int_counter_vec!(
@@ -13,4 +13,10 @@ lazy_static! {
         &["source"]
     )
     .unwrap();
+    pub static ref UNSTABLE_FILES_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "unstable_files",
+        "Number of matching files that are not enqueued yet because they may still be written to",
+        &["source"]
+    )
+    .unwrap();
 }
@@ -1,5 +1,11 @@
 use regex::Regex;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use tera::{Context, Tera};
+
+use cortex_core::filter::{FileProperties, Filter};
+use cortex_core::{HostKeyPolicy, MarkerAction, PostDownloadAction};
 
 extern crate regex;
 extern crate serde_regex;
@@ -17,22 +23,155 @@ pub struct SftpSource {
     pub username: String,
     pub password: Option<String>,
     pub key_file: Option<PathBuf>,
-    #[serde(with = "serde_regex")]
-    pub regex: Regex,
+    /// Regular expression on the file name, kept for configurations from
+    /// before `filter`
+    #[serde(with = "serde_regex", default)]
+    pub regex: Option<Regex>,
+    /// Filter on files, with paths relative to `directory`. Files must match
+    /// both `regex` and `filter` when both are specified.
+    pub filter: Option<Filter>,
     pub directory: String,
     #[serde(default = "default_false")]
     pub deduplicate: bool,
+    /// Delete remote files after download, the same as a 'delete' post
+    /// download action
     #[serde(default = "default_false")]
     pub remove: bool,
+    /// Action on remote files after they have been downloaded
+    #[serde(default)]
+    pub post_download: PostDownloadAction,
     pub scan_interval: u64,
     #[serde(default = "default_false")]
-    pub recurse: bool
+    pub recurse: bool,
+    /// OpenSSH known_hosts file used to verify the server host key
+    pub known_hosts: Option<PathBuf>,
+    /// Pinned host key fingerprints, e.g. 'SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8'
+    #[serde(default)]
+    pub host_key_fingerprints: Vec<String>,
+    /// Strict by default when known_hosts or host_key_fingerprints are
+    /// configured, off otherwise
+    pub host_key_policy: Option<HostKeyPolicy>,
+    /// Only enqueue files that are no longer being written to
+    pub stability: Option<Stability>,
+    /// Only enqueue files for which a completion marker file exists
+    pub marker: Option<MarkerRule>,
+}
+
+/// How the name of the marker file of a data file is derived
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkerName {
+    /// Suffix appended to the name of the data file, e.g. '.done'
+    Suffix(String),
+    /// Template for the name of the marker in the directory of the data
+    /// file, e.g. '{{ file_stem }}.ok'. Available are `file_name`,
+    /// `file_stem` and `extension`.
+    Template(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MarkerRule {
+    #[serde(flatten)]
+    pub name: MarkerName,
+    /// What the dispatcher does with the marker after downloading the file
+    #[serde(default)]
+    pub action: MarkerAction,
+}
+
+/// Criteria for a file to be considered completely uploaded by the remote
+/// party. A file is stable when all criteria are met.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Stability {
+    /// Number of consecutive scans that must observe the same size and
+    /// modification time
+    #[serde(default = "default_stability_scans")]
+    pub scans: u32,
+    /// Minimum time in milliseconds since the last modification. Not applied
+    /// when the server does not report modification times.
+    #[serde(default)]
+    pub min_age: u64,
 }
 
 fn default_false() -> bool {
     false
 }
 
+fn default_stability_scans() -> u32 {
+    1
+}
+
+/// Names of files used to check that renamed files are not picked up again
+const SAMPLE_FILE_NAMES: &[&str] = &["data", "data.xml", "data.csv", "data.json", "data.txt", "data.dat", "data.gz", "data.zip"];
+
+impl SftpSource {
+    pub fn post_download_action(&self) -> PostDownloadAction {
+        match &self.post_download {
+            PostDownloadAction::None if self.remove => PostDownloadAction::Delete,
+            action => action.clone()
+        }
+    }
+
+    /// Check a file against the regex and the filter of the source, with the
+    /// path of the file relative to the source directory
+    pub fn file_matches(&self, file: &FileProperties, now: DateTime<Utc>) -> bool {
+        if let Some(regex) = &self.regex {
+            let file_name = file.path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+
+            if !regex.is_match(&file_name) {
+                return false;
+            }
+        }
+
+        match &self.filter {
+            Some(filter) => filter.matches(file, now),
+            None => true
+        }
+    }
+
+    /// The directory that a file in `directory` is moved to after download,
+    /// when the post-download action is a move
+    pub fn move_directory(&self, directory: &Path) -> Option<PathBuf> {
+        match self.post_download_action() {
+            PostDownloadAction::Move { directory: move_directory } => Some(directory.join(move_directory)),
+            _ => None
+        }
+    }
+
+    /// Check that files renamed after download do not match the source
+    /// again, which would download them over and over. Checked for a set of
+    /// sample file names, as a 1 KiB file modified a day ago.
+    fn validate_rename(&self, template: &str) -> Result<(), String> {
+        let now = Utc::now();
+        let modified = now - Duration::days(1);
+
+        for sample in SAMPLE_FILE_NAMES {
+            let sample_path = Path::new(sample);
+            let lossy = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+
+            let mut context = Context::new();
+            context.insert("file_name", sample);
+            context.insert("file_stem", &lossy(sample_path.file_stem()));
+            context.insert("extension", &lossy(sample_path.extension()));
+            context.insert("timestamp", &now.format("%Y%m%dT%H%M%S").to_string());
+
+            let renamed = Tera::one_off(template, &context, false)
+                .map_err(|e| format!("invalid rename template '{}': {}", template, e))?;
+
+            let original = FileProperties { path: sample_path, size: Some(1024), modified: Some(modified) };
+            let renamed = FileProperties { path: Path::new(&renamed), ..original };
+
+            if self.file_matches(&original, now) && self.file_matches(&renamed, now) {
+                return Err(format!(
+                    "'{}' is renamed to '{}', which matches the source again",
+                    sample, renamed.path.to_string_lossy()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Postgresql {
     pub url: String,
@@ -52,6 +191,23 @@ pub struct Settings {
 }
 
 
+impl Settings {
+    /// Check the combinations of settings that deserialization cannot reject
+    pub fn validate(&self) -> Result<(), String> {
+        for sftp_source in &self.sftp_sources {
+            HostKeyPolicy::validate(sftp_source.host_key_policy, &sftp_source.known_hosts, &sftp_source.host_key_fingerprints)
+                .map_err(|e| format!("SFTP source '{}': {}", &sftp_source.name, e))?;
+
+            if let PostDownloadAction::Rename { template } = sftp_source.post_download_action() {
+                sftp_source.validate_rename(&template)
+                    .map_err(|e| format!("SFTP source '{}': {}", &sftp_source.name, e))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl Default for Settings {
     fn default() -> Self {
         Settings {
@@ -66,12 +222,22 @@ impl Default for Settings {
                     username: "cortex".to_string(),
                     password: Some("password".to_string()),
                     key_file: None,
-                    regex: Regex::new("^.*\\.xml$").unwrap(),
+                    regex: Some(Regex::new("^.*\\.xml$").unwrap()),
+                    filter: None,
                     directory: "upload/red".to_string(),
                     deduplicate: false,
                     remove: true,
+                    post_download: PostDownloadAction::None,
                     scan_interval: 3000,
                     recurse: false,
+                    known_hosts: Some(PathBuf::from("/etc/cortex/known_hosts")),
+                    host_key_fingerprints: vec![],
+                    host_key_policy: Some(HostKeyPolicy::AcceptNew),
+                    stability: Some(Stability {
+                        scans: 2,
+                        min_age: 60_000,
+                    }),
+                    marker: None,
                 },
                 SftpSource {
                     name: "blue".to_string(),
@@ -79,12 +245,22 @@ impl Default for Settings {
                     username: "cortex".to_string(),
                     password: Some("password".to_string()),
                     key_file: None,
-                    regex: Regex::new("^.*\\.xml$").unwrap(),
+                    regex: Some(Regex::new("^.*\\.xml$").unwrap()),
+                    filter: None,
                     directory: "upload/blue".to_string(),
                     deduplicate: false,
                     remove: true,
+                    post_download: PostDownloadAction::None,
                     scan_interval: 2000,
                     recurse: true,
+                    known_hosts: Some(PathBuf::from("/etc/cortex/known_hosts")),
+                    host_key_fingerprints: vec![],
+                    host_key_policy: Some(HostKeyPolicy::AcceptNew),
+                    stability: None,
+                    marker: Some(MarkerRule {
+                        name: MarkerName::Suffix(".done".to_string()),
+                        action: MarkerAction::Delete,
+                    }),
                 },
             ],
             postgresql: Postgresql {
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::fmt;
 use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
@@ -14,11 +15,15 @@ use retry::{retry, delay::Fixed, OperationResult};
 extern crate chrono;
 use chrono::prelude::*;
 
-use cortex_core::sftp_connection::{SftpConfig, SftpConnection};
-use cortex_core::SftpDownload;
+use cortex_core::sftp_connection::{HostKeyPolicy, SftpConfig, SftpConnection};
+use cortex_core::filter::FileProperties;
+use cortex_core::{Marker, PostDownloadAction, SftpDownload};
+
+use tera::{Context, Tera};
 
 use crate::metrics;
-use crate::settings::SftpSource;
+use crate::settings::{MarkerName, MarkerRule, SftpSource};
+use crate::stability::StabilityTracker;
 
 
 error_chain! {
@@ -64,15 +69,23 @@ pub fn start_scanner(
             password: sftp_source.password.clone(),
             key_file: sftp_source.key_file.clone(),
             compress: false,
+            known_hosts: sftp_source.known_hosts.clone(),
+            host_key_fingerprints: sftp_source.host_key_fingerprints.clone(),
+            host_key_policy: HostKeyPolicy::effective(sftp_source.host_key_policy, &sftp_source.known_hosts, &sftp_source.host_key_fingerprints),
         };
 
         let connect_result = SftpConnection::connect_loop(sftp_config.clone(), stop.clone());
 
         let sftp_connection = match connect_result {
             Ok(c) => Arc::new(RefCell::new(c)),
-            Err(e) => return Err(Error::with_chain(e, "Error reconnecting SFTP"))
+            Err(e) => {
+                error!("SFTP connect to {} for source '{}' failed: {}", sftp_config.address, sftp_source.name, e);
+                return Err(Error::with_chain(e, "Error connecting SFTP"))
+            }
         };
 
+        let mut stability_tracker = StabilityTracker::new();
+
         let scan_interval = time::Duration::from_millis(sftp_source.scan_interval);
         let mut next_scan = time::Instant::now();
 
@@ -88,8 +101,10 @@ pub fn start_scanner(
                 let scan_start = time::Instant::now();
                 info!("Started scanning {}", &sftp_source.name);
 
+                stability_tracker.begin_scan();
+
                 let scan_result = retry(Fixed::from_millis(1000), || {
-                    match scan_source(&stop, &sftp_source, sftp_connection.clone(), &mut conn, &mut sender) {
+                    match scan_source(&stop, &sftp_source, sftp_connection.clone(), &mut conn, &mut sender, &mut stability_tracker) {
                         Ok(v) => OperationResult::Ok(v),
                         Err(e) => {
                             match e {
@@ -129,6 +144,12 @@ pub fn start_scanner(
                             &sr
                         );
 
+                        if sftp_source.stability.is_some() {
+                            metrics::UNSTABLE_FILES_GAUGE
+                                .with_label_values(&[&sftp_source.name])
+                                .set(stability_tracker.finish_scan() as i64);
+                        }
+
                         metrics::DIR_SCAN_COUNTER
                             .with_label_values(&[&sftp_source.name])
                             .inc();
@@ -158,6 +179,10 @@ struct ScanResult {
     pub encountered_files: u64,
     /// Number of files that matched the criteria of the source
     pub matching_files: u64,
+    /// Number of matching files without a completion marker
+    pub unmarked_files: u64,
+    /// Number of matching files that may still be written to
+    pub unstable_files: u64,
     /// Number of files dispatched on the channel
     pub dispatched_files: u64
 }
@@ -167,6 +192,8 @@ impl ScanResult {
         ScanResult {
             encountered_files: 0,
             matching_files: 0,
+            unmarked_files: 0,
+            unstable_files: 0,
             dispatched_files: 0
         }
     }
@@ -174,21 +201,55 @@ impl ScanResult {
     fn add(&mut self, other: &ScanResult) {
         self.encountered_files += other.encountered_files;
         self.matching_files += other.encountered_files;
+        self.unmarked_files += other.unmarked_files;
+        self.unstable_files += other.unstable_files;
         self.dispatched_files += other.dispatched_files;
     }
 }
 
 impl fmt::Display for ScanResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "encountered: {}, matching: {}, dispatched: {}", self.encountered_files, self.matching_files, self.dispatched_files)
+        write!(
+            f, "encountered: {}, matching: {}, unmarked: {}, unstable: {}, dispatched: {}",
+            self.encountered_files, self.matching_files, self.unmarked_files, self.unstable_files, self.dispatched_files
+        )
+    }
+}
+
+/// Check a file against the regex and the filter of the source
+fn file_matches(sftp_source: &SftpSource, path: &Path, stat: &ssh2::FileStat) -> bool {
+    let file = FileProperties {
+        path: path.strip_prefix(&sftp_source.directory).unwrap_or(path),
+        size: stat.size,
+        modified: stat.mtime.and_then(|mtime| Utc.timestamp_opt(mtime as i64, 0).single()),
+    };
+
+    sftp_source.file_matches(&file, Utc::now())
+}
+
+/// Name of the completion marker of a data file
+fn marker_name(rule: &MarkerRule, file_name: &str) -> std::result::Result<String, tera::Error> {
+    match &rule.name {
+        MarkerName::Suffix(suffix) => Ok(format!("{}{}", file_name, suffix)),
+        MarkerName::Template(template) => {
+            let path = Path::new(file_name);
+            let lossy = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+
+            let mut context = Context::new();
+            context.insert("file_name", file_name);
+            context.insert("file_stem", &lossy(path.file_stem()));
+            context.insert("extension", &lossy(path.extension()));
+
+            Tera::one_off(template, &context, false)
+        }
     }
 }
 
-fn scan_source(stop: &Arc<AtomicBool>, sftp_source: &SftpSource, sftp_connection: Arc<RefCell<SftpConnection>>, conn: &mut postgres::Client, sender: &mut Sender<SftpDownload>) -> Result<ScanResult> {
-    scan_directory(stop, sftp_source, &Path::new(&sftp_source.directory), sftp_connection, conn, sender)
+fn scan_source(stop: &Arc<AtomicBool>, sftp_source: &SftpSource, sftp_connection: Arc<RefCell<SftpConnection>>, conn: &mut postgres::Client, sender: &mut Sender<SftpDownload>, stability_tracker: &mut StabilityTracker) -> Result<ScanResult> {
+    scan_directory(stop, sftp_source, &Path::new(&sftp_source.directory), sftp_connection, conn, sender, stability_tracker)
 }
 
-fn scan_directory(stop: &Arc<AtomicBool>, sftp_source: &SftpSource, directory: &Path, sftp_connection: Arc<RefCell<SftpConnection>>, conn: &mut postgres::Client, sender: &mut Sender<SftpDownload>) -> Result<ScanResult> {
+fn scan_directory(stop: &Arc<AtomicBool>, sftp_source: &SftpSource, directory: &Path, sftp_connection: Arc<RefCell<SftpConnection>>, conn: &mut postgres::Client, sender: &mut Sender<SftpDownload>, stability_tracker: &mut StabilityTracker) -> Result<ScanResult> {
     debug!("Directory scan started for {}", &directory.to_str().unwrap());
     let mut scan_result = ScanResult::new();
 
@@ -207,6 +268,14 @@ fn scan_directory(stop: &Arc<AtomicBool>, sftp_source: &SftpSource, directory: &
         }
     };
 
+    // Names in the directory, to look up the completion markers of files
+    let names: HashSet<String> = match sftp_source.marker {
+        Some(_) => paths.iter()
+            .filter_map(|(p, _)| p.file_name().map(|n| n.to_string_lossy().to_string()))
+            .collect(),
+        None => HashSet::new()
+    };
+
     for (path, stat) in paths {
         if stop.load(Ordering::Relaxed) {
             break;
@@ -217,7 +286,14 @@ fn scan_directory(stop: &Arc<AtomicBool>, sftp_source: &SftpSource, directory: &
         if stat.is_dir() && sftp_source.recurse {
             let mut dir = PathBuf::from(directory);
             dir.push(&file_name);
-            let result = scan_directory(stop, sftp_source, &dir, sftp_connection.clone(), conn, sender);
+
+            // Files that were moved after their download are not scanned again
+            if sftp_source.move_directory(directory).as_deref() == Some(dir.as_path()) {
+                debug!("Skipping move directory '{}'", dir.to_string_lossy());
+                continue;
+            }
+
+            let result = scan_directory(stop, sftp_source, &dir, sftp_connection.clone(), conn, sender, stability_tracker);
 
             match result {
                 Ok(sr) => {
@@ -247,13 +323,45 @@ fn scan_directory(stop: &Arc<AtomicBool>, sftp_source: &SftpSource, directory: &
 
             let path_str = path.to_str().unwrap().to_string();
 
-            if sftp_source.regex.is_match(file_name) {
+            if file_matches(sftp_source, &path, &stat) {
                 scan_result.matching_files += 1;
                 debug!("'{}' - matches", path_str);
 
+                let marker = match &sftp_source.marker {
+                    Some(rule) => {
+                        let marker_name = match marker_name(rule, file_name) {
+                            Ok(n) => n,
+                            Err(e) => {
+                                error!("Could not determine marker of '{}': {}", path_str, e);
+                                continue;
+                            }
+                        };
+
+                        if !names.contains(&marker_name) {
+                            scan_result.unmarked_files += 1;
+                            debug!("'{}' - no marker '{}'", path_str, marker_name);
+                            continue;
+                        }
+
+                        Some(Marker {
+                            path: path.with_file_name(&marker_name).to_string_lossy().to_string(),
+                            action: rule.action,
+                        })
+                    },
+                    None => None
+                };
+
+                if let Some(stability) = &sftp_source.stability {
+                    if !stability_tracker.observe(stability, &path_str, &stat) {
+                        scan_result.unstable_files += 1;
+                        debug!("'{}' - not stable yet", path_str);
+                        continue;
+                    }
+                }
+
                 let file_requires_download = if sftp_source.deduplicate {
                     let query_result = conn.query_one(
-                        "select count(*) from dispatcher.sftp_download where source = $1 and path = $2 and size = $3",
+                        "select count(*) from dispatcher.sftp_download where source = $1 and path = $2 and size = $3 and state <> 'vanished'",
                         &[&sftp_source.name, &path_str, &file_size_db]
                     );
 
@@ -273,7 +381,7 @@ fn scan_directory(stop: &Arc<AtomicBool>, sftp_source: &SftpSource, directory: &
 
                 if file_requires_download {
                     let insert_result = conn.query_one(
-                        "insert into dispatcher.sftp_download (source, path, size) values ($1, $2, $3) returning id",
+                        "insert into dispatcher.sftp_download (source, path, size, state) values ($1, $2, $3, 'queued') returning id",
                         &[&sftp_source.name, &path_str, &file_size_db]
                     );
     
@@ -286,13 +394,17 @@ fn scan_directory(stop: &Arc<AtomicBool>, sftp_source: &SftpSource, directory: &
                         }
                     };
     
+                    let post_download = sftp_source.post_download_action();
+
                     let command = SftpDownload {
                         id: sftp_download_id,
                         created: Utc::now(),
                         size: stat.size,
                         sftp_source: sftp_source.name.clone(),
                         path: path_str.clone(),
-                        remove: sftp_source.remove
+                        remove: post_download == PostDownloadAction::Delete,
+                        post_download,
+                        marker,
                     };
 
                     let retry_policy = Fixed::from_millis(100);
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use ssh2::FileStat;
+
+use crate::settings::Stability;
+
+/// Last observed state of a matching file
+struct Observation {
+    size: Option<u64>,
+    mtime: Option<u64>,
+    /// Number of consecutive scans that observed the same size and mtime
+    unchanged_scans: u32,
+    /// Scan in which the file was last observed
+    last_scan: u64,
+    /// Outcome of the last observation
+    stable: bool,
+}
+
+/// Keeps track of the files of a source over consecutive scans, to decide
+/// which files are no longer being written to.
+pub struct StabilityTracker {
+    observations: HashMap<String, Observation>,
+    scan: u64,
+}
+
+impl StabilityTracker {
+    pub fn new() -> StabilityTracker {
+        StabilityTracker {
+            observations: HashMap::new(),
+            scan: 0,
+        }
+    }
+
+    /// Start a new scan. Repeated observations within the same scan, e.g. when
+    /// a scan is retried after a reconnect, are counted once.
+    pub fn begin_scan(&mut self) {
+        self.scan += 1;
+    }
+
+    /// Record an observation of a file and return whether it is stable
+    pub fn observe(&mut self, stability: &Stability, path: &str, stat: &FileStat) -> bool {
+        let scan = self.scan;
+
+        let observation = self.observations.entry(path.to_string()).or_insert(Observation {
+            size: stat.size,
+            mtime: stat.mtime,
+            unchanged_scans: 0,
+            last_scan: 0,
+            stable: false,
+        });
+
+        if observation.size != stat.size || observation.mtime != stat.mtime {
+            observation.size = stat.size;
+            observation.mtime = stat.mtime;
+            observation.unchanged_scans = 1;
+        } else if observation.last_scan != scan {
+            observation.unchanged_scans += 1;
+        }
+
+        observation.last_scan = scan;
+
+        let old_enough = match stat.mtime {
+            Some(mtime) => {
+                let age_ms = (Utc::now().timestamp() - mtime as i64) * 1000;
+
+                age_ms >= stability.min_age as i64
+            },
+            None => true
+        };
+
+        observation.stable = observation.unchanged_scans >= stability.scans && old_enough;
+
+        observation.stable
+    }
+
+    /// Forget the files that were not observed in the current scan, because
+    /// they were removed or renamed, and return the number of tracked files
+    /// that are not stable yet.
+    pub fn finish_scan(&mut self) -> usize {
+        let scan = self.scan;
+
+        self.observations.retain(|_, o| o.last_scan == scan);
+
+        self.observations.values()
+            .filter(|o| !o.stable)
+            .count()
+    }
+}